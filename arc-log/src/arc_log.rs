@@ -1,43 +1,184 @@
 use alloc::alloc::{handle_alloc_error, Allocator, Global, Layout, LayoutError};
-// use alloc::collections::TryReserveError::{self, *};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 
 use core::cell::UnsafeCell;
 use core::cmp;
 use core::fmt;
+use core::future::Future;
 use core::marker::PhantomData;
 use core::mem::{self, ManuallyDrop, MaybeUninit};
 use core::ops::Deref;
 use core::ops::Index;
-use core::ptr::Pointee;
-use core::ptr::Thin;
+use core::ops::{Range, RangeFrom, RangeFull, RangeInclusive, RangeTo, RangeToInclusive};
+use core::pin::Pin;
 use core::ptr::addr_of;
 use core::ptr::addr_of_mut;
 use core::ptr::{self, NonNull};
 use core::slice;
 use core::slice::SliceIndex;
-use core::sync::atomic::{AtomicIsize, AtomicPtr, AtomicUsize, Ordering::*};
+use crate::sync::hint;
+use crate::sync::{AtomicUsize, Ordering::*};
+use core::task::{Context, Poll};
 use tracing::{event, instrument, Level};
 
-// pub unsafe auto trait Freeze {}
-// impl<T: ?Sized> !Freeze for UnsafeCell<T> {}
-// unsafe impl<T: ?Sized> Freeze for &T {}
-// unsafe impl<T: ?Sized> Freeze for &mut T {}
-// unsafe impl<T: ?Sized> Freeze for *const T {}
-// unsafe impl<T: ?Sized> Freeze for *mut T {}
-// unsafe impl<T: ?Sized> Freeze for PhantomData<T> {}
+#[cfg(feature = "futures-core")]
+use futures_core::Stream;
+#[cfg(feature = "serde")]
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::backoff::{Backoff, Spin};
+use crate::chain::{self, drop_ref, Node};
+use crate::growth_policy::{grown_capacity, Doubling, GrowthPolicy};
+use crate::waker_list::{WakerHeader, WakerKey};
+
+/// Types that can be relocated with a bitwise copy while shared references to the
+/// original are still live. ArcLog copies (not moves) its items when it grows, so this
+/// only holds for types without interior mutability. Anything that owns an UnsafeCell
+/// inline is excluded, while pointers to one are fine because the pointee doesn't move.
+///
+/// # Safety
+///
+/// Implementing it by hand asserts the type has no interior mutability of its own,
+/// a bitwise copy made while it's shared has to be as good as the original.
+///
+/// ```
+/// let mut log = arc_log::ArcLog::<String>::new();
+/// log.push_spin(String::from("frozen"));
+/// ```
+///
+/// AtomicU64 is Sync, so only the Freeze bound keeps it out:
+///
+/// ```compile_fail
+/// use core::sync::atomic::AtomicU64;
+/// let log = arc_log::ArcLog::<AtomicU64>::new();
+/// ```
+pub unsafe auto trait Freeze {}
+impl<T: ?Sized> !Freeze for UnsafeCell<T> {}
+unsafe impl<T: ?Sized> Freeze for &T {}
+unsafe impl<T: ?Sized> Freeze for &mut T {}
+unsafe impl<T: ?Sized> Freeze for *const T {}
+unsafe impl<T: ?Sized> Freeze for *mut T {}
+unsafe impl<T: ?Sized> Freeze for NonNull<T> {}
+unsafe impl<T: ?Sized> Freeze for PhantomData<T> {}
+
+/// The error type for the `try_` methods of [`ArcLog`], mirrors
+/// `alloc::collections::TryReserveError` but can be built outside of alloc.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TryReserveError {
+    kind: TryReserveErrorKind,
+}
+
+// non_exhaustive like std's, so a new way to fail isn't a breaking change
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TryReserveErrorKind {
+    /// the requested capacity doesn't fit the len encoding or a `Layout`
+    CapacityOverflow,
+    /// the allocator returned an error
+    AllocError { layout: Layout },
+}
+
+impl TryReserveError {
+    pub fn kind(&self) -> TryReserveErrorKind {
+        self.kind.clone()
+    }
+}
+
+impl From<TryReserveErrorKind> for TryReserveError {
+    fn from(kind: TryReserveErrorKind) -> Self {
+        TryReserveError { kind }
+    }
+}
+
+impl From<LayoutError> for TryReserveError {
+    fn from(_: LayoutError) -> Self {
+        TryReserveErrorKind::CapacityOverflow.into()
+    }
+}
+
+impl fmt::Display for TryReserveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("memory allocation failed")?;
+        match self.kind {
+            TryReserveErrorKind::CapacityOverflow => {
+                f.write_str(" because the computed capacity exceeded the log's maximum")
+            }
+            TryReserveErrorKind::AllocError { .. } => {
+                f.write_str(" because the memory allocator returned an error")
+            }
+        }
+    }
+}
+
+impl core::error::Error for TryReserveError {}
+
+/// A failed [`ArcLog::try_push_spin`], holds on to the item that wasn't pushed
+pub struct TryPushError<T> {
+    item: T,
+    error: TryReserveError,
+}
+
+impl<T> TryPushError<T> {
+    pub fn error(&self) -> &TryReserveError {
+        &self.error
+    }
+
+    pub fn into_inner(self) -> T {
+        self.item
+    }
+}
+
+impl<T> fmt::Debug for TryPushError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TryPushError")
+            .field("error", &self.error)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> fmt::Display for TryPushError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+
+// same as RawVec, the infallible paths turn the errors into panics or the alloc error hook
+pub(crate) fn handle_reserve<R>(result: Result<R, TryReserveError>) -> R {
+    match result {
+        Ok(r) => r,
+        Err(TryReserveError {
+            kind: TryReserveErrorKind::CapacityOverflow,
+        }) => panic!("capacity overflow"),
+        Err(TryReserveError {
+            kind: TryReserveErrorKind::AllocError { layout },
+        }) => handle_alloc_error(layout),
+    }
+}
 
 pub struct ArcLog<T, A: Allocator= Global> {
     ptr: NonNull<ArcLogInner<T, A>>,
     pd: PhantomData<ArcLogInner<T, A>>,
 }
 
-impl<T: Sync> ArcLog<T> {
-    pub unsafe fn new() -> Self {
+impl<T: Sync + Freeze> ArcLog<T> {
+    pub fn new() -> Self {
         ArcLog::new_in(Global)
     }
-    pub unsafe fn with_capacity(capacity: usize) -> Self {
+    pub fn with_capacity(capacity: usize) -> Self {
         ArcLog::with_capacity_in(capacity, Global)
     }
+
+    /// starts with room for `capacity` items and grows according to `policy` from there
+    pub fn with_policy<G: GrowthPolicy + 'static>(capacity: usize, policy: G) -> Self {
+        ArcLog::with_policy_in(capacity, policy, Global)
+    }
+}
+
+impl<T: Sync + Freeze> Default for ArcLog<T> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 unsafe impl<T, A: Allocator> Send for ArcLog<T, A> {}
@@ -56,30 +197,102 @@ impl<T, A: Allocator> Drop for ArcLog<T, A> {
 // and have bound, or ignore them so you can always debug
 impl<T: fmt::Debug, A: Allocator> fmt::Debug for ArcLog<T, A> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let header_r = unsafe { &(*self.ptr.as_ptr()).header};
-        let len_raw = header_r.len.load(Acquire);
+        // raw, a reference to the header would read forward while a writer may set it
+        let header = unsafe { addr_of!((*self.ptr.as_ptr()).header) };
+        let len_raw = unsafe { (*header).len.load(Acquire) };
         let len = get_len(len_raw);
         let is_locked = is_locked(len_raw);
         let has_forward = has_forward(len_raw);
+        // forward is only safe to read once the bit says it was written
+        let (forward, count, cap, start_index) = unsafe {
+            let forward = if has_forward { (*header).forward.with(|f| *f) } else { None };
+            (forward, (*header).count.load(Relaxed), (*header).cap, (*header).start_index)
+        };
+        // pinned to the len we just read, so the data can't disagree with it
+        let snapshot = ArcLogSnapshot::new(self.ptr, len);
         f.debug_struct("ArcLog")
             .field("ptr", &self.ptr)
-            .field("forward", &header_r.forward)
-            .field("count", &header_r.count.load(Relaxed))
-            .field("cap", &header_r.cap)
+            .field("forward", &forward)
+            .field("count", &count)
+            .field("cap", &cap)
+            .field("start_index", &start_index)
             .field("len_raw", &len_raw)
             .field("is_locked", &is_locked)
             .field("has_forward", &has_forward)
             .field("len", &len)
-            .field("data", &&**self)
+            .field("data", &&*snapshot)
             .finish()
     }
 }
 
-impl<T, A: Allocator, I: SliceIndex<[T]>> Index<I> for ArcLog<T, A> {
+/// Indexing uses the same global indices that the push methods return, even after
+/// a compaction. Dereferencing gives the slice of items that are still retained.
+impl<T, A: Allocator, I: LogIndex + SliceIndex<[T]>> Index<I> for ArcLog<T, A> {
     type Output = I::Output;
     #[inline]
     fn index(&self, index: I) -> &Self::Output {
-        Index::index(&**self, index)
+        Index::index(&**self, index.rebase(self.start_index()))
+    }
+}
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// Index types that can be shifted from global log indices to slice indices
+pub trait LogIndex: private::Sealed {
+    #[doc(hidden)]
+    fn rebase(self, start: usize) -> Self;
+}
+
+#[inline]
+fn rebase_index(index: usize, start: usize) -> usize {
+    match index.checked_sub(start) {
+        Some(i) => i,
+        None => panic!("index {} was compacted away, the log starts at {}", index, start),
+    }
+}
+
+impl private::Sealed for usize {}
+impl LogIndex for usize {
+    fn rebase(self, start: usize) -> Self {
+        rebase_index(self, start)
+    }
+}
+impl private::Sealed for Range<usize> {}
+impl LogIndex for Range<usize> {
+    fn rebase(self, start: usize) -> Self {
+        rebase_index(self.start, start)..rebase_index(self.end, start)
+    }
+}
+impl private::Sealed for RangeFrom<usize> {}
+impl LogIndex for RangeFrom<usize> {
+    fn rebase(self, start: usize) -> Self {
+        rebase_index(self.start, start)..
+    }
+}
+impl private::Sealed for RangeTo<usize> {}
+impl LogIndex for RangeTo<usize> {
+    fn rebase(self, start: usize) -> Self {
+        ..rebase_index(self.end, start)
+    }
+}
+impl private::Sealed for RangeInclusive<usize> {}
+impl LogIndex for RangeInclusive<usize> {
+    fn rebase(self, start: usize) -> Self {
+        rebase_index(*self.start(), start)..=rebase_index(*self.end(), start)
+    }
+}
+impl private::Sealed for RangeToInclusive<usize> {}
+impl LogIndex for RangeToInclusive<usize> {
+    fn rebase(self, start: usize) -> Self {
+        ..=rebase_index(self.end, start)
+    }
+}
+impl private::Sealed for RangeFull {}
+impl LogIndex for RangeFull {
+    fn rebase(self, _start: usize) -> Self {
+        self
     }
 }
 
@@ -101,103 +314,55 @@ impl<T, A: Allocator> Deref for ArcLog<T, A> {
         // should decide if we should chase to the next here
         len = get_len(len);
        // unsafe { MaybeUninit::slice_assume_init_ref(slice::from_raw_parts(inner.data.get() as *const MaybeUninit<_>, len as usize)) }
-       unsafe { MaybeUninit::slice_assume_init_ref(slice::from_raw_parts(addr_of!((*self.ptr.as_ptr()).data) as *const MaybeUninit<_>, len as usize)) }
-    }
-}
-
-#[inline(never)]
-//#[instrument]
-fn drop_ref<T, A: Allocator>(ptr: NonNull<ArcLogInner<T, A>>) {
-    event!(Level::TRACE, "enter drop_ref");
-    // this is release because we need to capture all loads before we deallocate.
-    // in theory we only need need to do this for the last subtraction, but we don't
-    // have another clear place to do this, and this is what Arc does...
-    let prev_val =  unsafe {(*ptr.as_ptr()).header.count.fetch_sub(1, Release)};
-    if prev_val != 1 {
-        event!(Level::TRACE, "more than one ref, no need to drop");
-        return;
-    }
-    event!(Level::TRACE, "last ref so have to drop");
-    // functionally, this does nothing, but we need a release barrier here so writes don't creep up from the destructors
-    // ref_ptr.header.count.store(0, Acquire);
-    // the previous check guarantees we have exclusive access
-    unsafe {(*ptr.as_ptr()).header.count.load(Acquire)};
-    let raw_len = unsafe {(*ptr.as_ptr()).header.len.load(Acquire)};
-    let forward_ptr = unsafe { (*ptr.as_ptr()).header.forward};
-
-    
-
-    match forward_ptr {
-        Some(f_ptr) => {
-            // if there is a forwarding address, we can just deallocate because the forward is responsible for dropping the inners
-            event!(Level::TRACE, "forward isn't null so we reenter drop_ref");
-            // SAFETY: We just checked it was null and a forward pointer should always be valid
-            drop_ref::<T,A>(f_ptr);
-            event!(Level::TRACE, "finished reentry of drop_ref");
-        },
-        None => {
-            let len_to_drop =  get_len(raw_len);
-            event!(
-                Level::TRACE,
-                "forward pointer is null, with {:?} items to drop",
-                len_to_drop
-            );
-            unsafe {
-                ptr::drop_in_place(ptr::slice_from_raw_parts_mut(
-                    addr_of_mut!((*ptr.as_ptr()).data) as *mut T,
-                    len_to_drop,
-                ));
-            }
-            event!(Level::TRACE, "drop items");
-        }
-    } 
-
-    //let alloc_ref = unsafe { (*ptr.as_ptr()).header.alloc };
-    let alloc_ref = unsafe { &(*ptr.as_ptr()).header.alloc };
-    unsafe {
-        event!(Level::TRACE, "calling dealloc");
-        // SAFETY: We are the last reference so we need to deallocate
-        alloc_ref.deallocate(
-            NonNull::new_unchecked(ptr.as_ptr() as *mut u8),
-            ArcLogInner::<T, A>::get_layout((*ptr.as_ptr()).header.cap),
-        );
-        event!(Level::TRACE, "dealloc completed");
+       unsafe { MaybeUninit::slice_assume_init_ref(slice::from_raw_parts(ArcLogInner::data_ptr(self.ptr) as *const MaybeUninit<_>, len as usize)) }
     }
 }
 
-
-impl<T: Sync, A: Allocator + Clone> ArcLog<T, A> {
+impl<T: Sync + Freeze, A: Allocator + Clone> ArcLog<T, A> {
     pub fn new_in(alloc: A) -> Self {
-        ArcLog {
-            ptr: ArcLogInner::with_capacity(0, alloc),
-            pd: PhantomData,
-        }
+        Self::with_capacity_in(0, alloc)
     }
 
     pub fn with_capacity_in(capacity: usize, alloc: A) -> Self {
-        ArcLog {
-            ptr: ArcLogInner::with_capacity(capacity, alloc),
+        handle_reserve(Self::try_with_capacity_in(capacity, alloc))
+    }
+
+    /// like with_capacity_in, but returns an error instead of aborting if the allocator fails
+    pub fn try_with_capacity_in(capacity: usize, alloc: A) -> Result<Self, TryReserveError> {
+        Self::try_with_policy_in(capacity, Doubling, alloc)
+    }
+
+    pub fn with_policy_in<G: GrowthPolicy + 'static>(capacity: usize, policy: G, alloc: A) -> Self {
+        handle_reserve(Self::try_with_policy_in(capacity, policy, alloc))
+    }
+
+    pub fn try_with_policy_in<G: GrowthPolicy + 'static>(
+        capacity: usize,
+        policy: G,
+        alloc: A,
+    ) -> Result<Self, TryReserveError> {
+        Ok(ArcLog {
+            ptr: ArcLogInner::try_with_capacity(capacity, policy, alloc)?,
             pd: PhantomData,
-        }
+        })
     }
 
     #[instrument(skip(self))]
     pub fn update(&mut self) -> bool {
-        let header = unsafe { &(*self.ptr.as_ptr()).header};
-        let raw_len = header.len.load(Acquire);
+        // only raw accesses to the header, a reference to it would read forward while
+        // the writer holding the lock on the tail may be setting it
+        let raw_len = unsafe { (*self.ptr.as_ptr()).header.len.load(Acquire) };
         if !has_forward(raw_len) {
             false
         } else {
             // SAFETY: We just checked for null, and forward must be valid if it exists
-            let mut p_this = unsafe { (header.forward).unwrap() };
-            let mut r_this = unsafe { p_this.as_ref() };
+            let mut p_this = unsafe { (*self.ptr.as_ptr()).header.forward.with(|f| (*f).unwrap()) };
             loop {
                 // this has to be acquire, because we may access data after this forward
-                let raw_len = r_this.header.len.load(Acquire);
+                let raw_len = unsafe { (*p_this.as_ptr()).header.len.load(Acquire) };
                 if has_forward(raw_len) {
                     event!(Level::TRACE, "had to forward");
-                    p_this = unsafe { (r_this.header.forward).unwrap() };
-                    r_this = unsafe { p_this.as_ref() };
+                    p_this = unsafe { (*p_this.as_ptr()).header.forward.with(|f| (*f).unwrap()) };
                 } else {
                     event!(Level::TRACE, "at end of forward change");
                     break;
@@ -207,18 +372,13 @@ impl<T: Sync, A: Allocator + Clone> ArcLog<T, A> {
             self.ptr = p_this;
             // this can be relaxed because only the count matters
             // and we already did an acquire when we got the pointer
-            r_this.header.count.fetch_add(1, Relaxed);
+            unsafe { (*p_this.as_ptr()).header.count.fetch_add(1, Relaxed) };
             drop_ref(old_ptr);
             true
         }
     }
 
-    fn finish_push(
-        &mut self,
-        index: isize,
-        o_ptr: Option<NonNull<ArcLogInner<T, A>>>,
-        item: T,
-    ) -> Result<usize, T> {
+    fn finish_alloc(&mut self, o_ptr: Option<NonNull<ArcLogInner<T, A>>>) {
         if let Some(new_ptr) = o_ptr {
             event!(Level::TRACE, "post alloc_one");
             let old_ptr = self.ptr;
@@ -228,6 +388,15 @@ impl<T: Sync, A: Allocator + Clone> ArcLog<T, A> {
             drop_ref(old_ptr);
             event!(Level::TRACE, "post drop ref")
         }
+    }
+
+    fn finish_push(
+        &mut self,
+        index: isize,
+        o_ptr: Option<NonNull<ArcLogInner<T, A>>>,
+        item: T,
+    ) -> Result<usize, T> {
+        self.finish_alloc(o_ptr);
         event!(Level::TRACE, "finished push");
         if index == -1 {
             Err(item)
@@ -237,32 +406,584 @@ impl<T: Sync, A: Allocator + Clone> ArcLog<T, A> {
         }
     }
 
+    fn finish_try_push(
+        &mut self,
+        index: Result<isize, TryReserveError>,
+        o_ptr: Option<NonNull<ArcLogInner<T, A>>>,
+        item: T,
+    ) -> Result<usize, TryPushError<T>> {
+        match index {
+            Ok(index) => self
+                .finish_push(index, o_ptr, item)
+                .map_err(|_| unreachable!()),
+            Err(error) => {
+                self.finish_alloc(o_ptr);
+                Err(TryPushError { item, error })
+            }
+        }
+    }
+
     /// returns the index of the item that was pushed
     #[instrument(skip(self, item))]
     pub fn push_spin(&mut self, item: T) -> usize {
-        let (index, o_ptr) = ArcLogInner::alloc_items(self.ptr, &item, 1, isize::MAX);
-        match self.finish_push(index, o_ptr, item) {
+        self.push_with_backoff(item, Spin)
+    }
+
+    /// Same as push_spin, but `backoff` decides what to do while another writer holds
+    /// the lock, see [`crate::backoff`]. Returns the index of the item that was pushed
+    #[instrument(skip(self, item, backoff))]
+    pub fn push_with_backoff<B: Backoff>(&mut self, item: T, mut backoff: B) -> usize {
+        let (index, o_ptr) = ArcLogInner::alloc_items(self.ptr, &item, 1, usize::MAX, &mut backoff);
+        match self.finish_push(handle_reserve(index), o_ptr, item) {
             Ok(i) => i,
             Err(_) => unreachable!(),
         }
     }
 
+    /// Same as push_spin, but if the log has to grow and the allocator fails, the
+    /// item is handed back with the error instead of aborting. The lock is released
+    /// either way, so other writers can carry on.
+    #[instrument(skip(self, item))]
+    pub fn try_push_spin(&mut self, item: T) -> Result<usize, TryPushError<T>> {
+        let (index, o_ptr) = ArcLogInner::alloc_items(self.ptr, &item, 1, usize::MAX, &mut Spin);
+        self.finish_try_push(index, o_ptr, item)
+    }
+
     #[instrument(skip(self, item))]
     pub fn push_or_return(&mut self, item: T) -> Result<usize, T> {
-        let (index, o_ptr) = ArcLogInner::alloc_items_one_shot(self.ptr, &item, 1, isize::MAX as usize);
-        self.finish_push(index, o_ptr, item)
+        let (index, o_ptr) = ArcLogInner::alloc_items_one_shot(self.ptr, &item, 1, usize::MAX);
+        self.finish_push(handle_reserve(index), o_ptr, item)
     }
     #[instrument(skip(self, item))]
     pub fn push_spin_by_index(&mut self, item: T, index: usize) -> Result<usize, T> {
-        let (index, o_ptr) = ArcLogInner::alloc_items(self.ptr, &item, 1, index as isize);
-        self.finish_push(index, o_ptr, item)
+        let (index, o_ptr) = ArcLogInner::alloc_items(self.ptr, &item, 1, index, &mut Spin);
+        self.finish_push(handle_reserve(index), o_ptr, item)
     }
     pub fn push_or_return_by_index(&mut self, item: T, index: usize) -> Result<usize, T> {
-        let (index, o_ptr) = ArcLogInner::alloc_items_one_shot(self.ptr, &item, 1, index as isize as usize);
-        self.finish_push(index, o_ptr, item)
+        let (index, o_ptr) = ArcLogInner::alloc_items_one_shot(self.ptr, &item, 1, index);
+        self.finish_push(handle_reserve(index), o_ptr, item)
+    }
+
+    /// Pushes the item only if `pred` returns true for the items already in the log,
+    /// otherwise hands it back. `pred` runs while holding the writer lock, so nothing
+    /// can be appended between the check and the push, which makes it usable for things
+    /// like dedup or "only if the last entry is N". The slice starts at the
+    /// [`start_index`](Self::start_index) of the tail, compacted items aren't in it.
+    ///
+    /// Other writers wait on `pred`, so keep it short. A zero sized log never locks,
+    /// so there `pred` runs again each time another push gets in first.
+    #[instrument(skip(self, item, pred))]
+    pub fn push_if<F: FnMut(&[T]) -> bool>(&mut self, item: T, mut pred: F) -> Result<usize, T> {
+        let (index, o_ptr) = ArcLogInner::alloc_items_if(self.ptr, &item, 1, &mut pred, &mut Spin);
+        self.finish_push(handle_reserve(index), o_ptr, item)
+    }
+
+    /// Makes sure at least `additional` more items fit without another reallocation,
+    /// moving the log to a bigger allocation now if they don't. This handle follows
+    /// the move, other handles pick it up on their next update.
+    #[instrument(skip(self))]
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        if mem::size_of::<T>() == 0 {
+            return Ok(());
+        }
+        let (p_this, len) = match chain::lock_tail(self.ptr, Some(&mut Spin)) {
+            Ok(locked) => locked,
+            Err(_) => unreachable!(),
+        };
+        let result = ArcLogInner::reserve_locked(p_this, len, additional);
+        let p_this = match result {
+            Ok(p_new) => p_new,
+            Err(_) => p_this,
+        };
+        self.finish_alloc(ArcLogInner::moved(self.ptr, p_this));
+        result.map(|_| ())
+    }
+
+    /// Same as try_reserve, but panics or aborts like push_spin if the allocation fails.
+    /// Pre-sizing keeps the copy of a reallocation off the next pushes.
+    pub fn reserve(&mut self, additional: usize) {
+        handle_reserve(self.try_reserve(additional))
+    }
+
+    /// Moves the log to an allocation that fits exactly the items it holds, leaving
+    /// behind anything before the compaction point. Like reserve, the move is only
+    /// picked up by other handles on their next update.
+    #[instrument(skip(self))]
+    pub fn shrink_to_fit(&mut self) {
+        if mem::size_of::<T>() == 0 {
+            return;
+        }
+        let (p_this, len) = match chain::lock_tail(self.ptr, Some(&mut Spin)) {
+            Ok(locked) => locked,
+            Err(_) => unreachable!(),
+        };
+        let p_this = handle_reserve(ArcLogInner::shrink_locked(p_this, len));
+        self.finish_alloc(ArcLogInner::moved(self.ptr, p_this));
+    }
+
+    /// resolves once `len` items have been pushed in total (compacted ones included),
+    /// updating this handle along the way
+    pub fn wait_for_len(&mut self, len: usize) -> WaitForLen<'_, T, A> {
+        WaitForLen {
+            log: self,
+            len,
+            waker_key: None,
+        }
+    }
+
+    /// follows everything appended from the current end onward
+    pub fn tail(&self) -> ArcLogTail<T, A> {
+        let next = self.end_index();
+        self.tail_from(next)
+    }
+
+    /// follows everything appended from `index` onward, the first range
+    /// covers any items already past `index`
+    pub fn tail_from(&self, index: usize) -> ArcLogTail<T, A> {
+        ArcLogTail {
+            log: self.clone(),
+            next: index,
+            waker_key: None,
+        }
+    }
+
+    /// copies the whole slice onto the end of the log as one contiguous run,
+    /// returns the index of the first item. An empty slice doesn't take the lock,
+    /// it moves to the newest allocation and returns the global index the next
+    /// item would get
+    #[instrument(skip(self, items))]
+    pub fn push_slice_spin(&mut self, items: &[T]) -> usize
+    where
+        T: Copy,
+    {
+        if items.is_empty() {
+            self.update();
+            return self.end_index();
+        }
+        let (index, o_ptr) = ArcLogInner::alloc_items(self.ptr, items.as_ptr(), items.len(), usize::MAX, &mut Spin);
+        self.finish_alloc(o_ptr);
+        handle_reserve(index) as usize
+    }
+
+    /// same as push_slice_spin, but hands the slice back if the lock wasn't available
+    #[instrument(skip(self, items))]
+    pub fn push_slice_or_return<'a>(&mut self, items: &'a [T]) -> Result<usize, &'a [T]>
+    where
+        T: Copy,
+    {
+        if items.is_empty() {
+            self.update();
+            return Ok(self.end_index());
+        }
+        let (index, o_ptr) = ArcLogInner::alloc_items_one_shot(self.ptr, items.as_ptr(), items.len(), usize::MAX);
+        self.finish_alloc(o_ptr);
+        let index = handle_reserve(index);
+        if index == -1 {
+            Err(items)
+        } else {
+            Ok(index as usize)
+        }
+    }
+
+    /// Moves every item from the iterator onto the end of the log under a single
+    /// lock acquisition, returns the index of the first item. The items are collected
+    /// into a temporary Vec first, so the iterator never runs while the lock is held,
+    /// at the cost of that extra allocation.
+    #[instrument(skip(self, iter))]
+    pub fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) -> usize {
+        let mut items: Vec<T> = iter.into_iter().collect();
+        if items.is_empty() {
+            self.update();
+            return self.end_index();
+        }
+        let (index, o_ptr) = ArcLogInner::alloc_items(self.ptr, items.as_ptr(), items.len(), usize::MAX, &mut Spin);
+        self.finish_alloc(o_ptr);
+        // on error the items weren't copied, so the Vec still owns them and drops them
+        let index = handle_reserve(index);
+        // SAFETY: the items were copied into the log, which now owns them
+        unsafe { items.set_len(0) };
+        index as usize
+    }
+}
+
+impl<A: Allocator + Clone> ArcLog<(), A> {
+    /// Appends `count` units in one step and returns the index of the first. A log of
+    /// () never takes the lock, so this works as a counter handing out ticket indices.
+    #[instrument(skip(self))]
+    pub fn push_n(&mut self, count: usize) -> usize {
+        if count == 0 {
+            self.update();
+            return self.end_index();
+        }
+        let (index, _) = ArcLogInner::alloc_items(self.ptr, NonNull::dangling().as_ptr(), count, usize::MAX, &mut Spin);
+        handle_reserve(index) as usize
+    }
+}
+
+impl<T, A: Allocator> ArcLog<T, A> {
+    fn shared(&self) -> &ArcLogShared {
+        unsafe { (*self.ptr.as_ptr()).header.shared.as_ref() }
+    }
+
+    pub(crate) fn allocator(&self) -> &A {
+        unsafe { &(*self.ptr.as_ptr()).header.alloc }
+    }
+
+    /// global index of the first item still retained by the allocation this handle points at
+    pub fn start_index(&self) -> usize {
+        unsafe { (*self.ptr.as_ptr()).header.start_index }
+    }
+
+    /// number of items the allocation this handle points at can hold,
+    /// compacted items aren't counted
+    pub fn capacity(&self) -> usize {
+        unsafe { (*self.ptr.as_ptr()).header.cap }
+    }
+
+    /// global index one past the last item this handle can see
+    pub fn end_index(&self) -> usize {
+        self.start_index() + self.len()
+    }
+
+    /// Lets the next reallocation leave every item before `index` behind instead of
+    /// copying it. Indices don't change, the compacted items are dropped once the last
+    /// handle on the allocation that still holds them updates or goes away.
+    pub fn compact_before(&self, index: usize) {
+        self.shared().compact_before.fetch_max(index, Relaxed);
+    }
+}
+
+/// Future returned by [`ArcLog::wait_for_len`]
+pub struct WaitForLen<'a, T, A: Allocator = Global> {
+    log: &'a mut ArcLog<T, A>,
+    len: usize,
+    waker_key: Option<WakerKey>,
+}
+
+impl<'a, T: Sync + Freeze, A: Allocator + Clone> Future for WaitForLen<'a, T, A> {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = &mut *self;
+        this.log.update();
+        if this.log.end_index() >= this.len {
+            return Poll::Ready(());
+        }
+        this.waker_key = Some(this.log.shared().wakers.register(this.waker_key, cx.waker()));
+        // an append between the first check and registering wouldn't wake us
+        this.log.update();
+        if this.log.end_index() >= this.len {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<'a, T, A: Allocator> Drop for WaitForLen<'a, T, A> {
+    fn drop(&mut self) {
+        if let Some(key) = self.waker_key.take() {
+            self.log.shared().wakers.deregister(key);
+        }
+    }
+}
+
+/// Follows the end of an ArcLog, handing out the index range of each new batch of items.
+/// The items themselves are read through [`ArcLogTail::log`].
+pub struct ArcLogTail<T, A: Allocator = Global> {
+    log: ArcLog<T, A>,
+    next: usize,
+    waker_key: Option<WakerKey>,
+}
+
+impl<T, A: Allocator> ArcLogTail<T, A> {
+    pub fn log(&self) -> &ArcLog<T, A> {
+        &self.log
+    }
+
+    /// index of the first item that hasn't been handed out yet
+    pub fn next_index(&self) -> usize {
+        self.next
+    }
+}
+
+impl<T: Sync + Freeze, A: Allocator + Clone> ArcLogTail<T, A> {
+    fn take_range(&mut self) -> Option<Range<usize>> {
+        self.log.update();
+        let len = self.log.end_index();
+        if len > self.next {
+            let range = self.next..len;
+            self.next = len;
+            Some(range)
+        } else {
+            None
+        }
+    }
+
+    /// resolves with the range of items appended since the last range
+    pub fn poll_next_range(&mut self, cx: &mut Context<'_>) -> Poll<Range<usize>> {
+        if let Some(range) = self.take_range() {
+            return Poll::Ready(range);
+        }
+        self.waker_key = Some(self.log.shared().wakers.register(self.waker_key, cx.waker()));
+        match self.take_range() {
+            Some(range) => Poll::Ready(range),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<T, A: Allocator> Unpin for ArcLogTail<T, A> {}
+
+impl<T, A: Allocator> Drop for ArcLogTail<T, A> {
+    fn drop(&mut self) {
+        if let Some(key) = self.waker_key.take() {
+            self.log.shared().wakers.deregister(key);
+        }
+    }
+}
+
+/// An ArcLog never ends, so this never yields `None`
+#[cfg(feature = "futures-core")]
+impl<T: Sync + Freeze, A: Allocator + Clone> Stream for ArcLogTail<T, A> {
+    type Item = Range<usize>;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.poll_next_range(cx).map(Some)
+    }
+}
+
+/// Serializes the items of a snapshot as a sequence
+#[cfg(feature = "serde")]
+impl<T: Serialize, A: Allocator> Serialize for ArcLog<T, A> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.snapshot().serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<T: Serialize, A: Allocator> Serialize for ArcLogSnapshot<T, A> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+/// Deserializes a sequence into a new log with exactly enough capacity for it.
+/// Goes through the same constructor as `ArcLog::new`, so `T` still has to be Freeze
+#[cfg(feature = "serde")]
+impl<'de, T: Deserialize<'de> + Sync + Freeze> Deserialize<'de> for ArcLog<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct LogVisitor<T>(PhantomData<T>);
+
+        impl<'de, T: Deserialize<'de> + Sync + Freeze> de::Visitor<'de> for LogVisitor<T> {
+            type Value = ArcLog<T>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+                formatter.write_str("a sequence")
+            }
+
+            fn visit_seq<S: de::SeqAccess<'de>>(self, mut seq: S) -> Result<ArcLog<T>, S::Error> {
+                // the size hint can't be trusted, so collect first and size the log after
+                let mut items = Vec::new();
+                while let Some(item) = seq.next_element()? {
+                    items.push(item);
+                }
+                let mut log = ArcLog::with_capacity(items.len());
+                log.extend(items);
+                Ok(log)
+            }
+        }
+
+        deserializer.deserialize_seq(LogVisitor(PhantomData))
+    }
+}
+
+/// A view of an [`ArcLog`] pinned to the len it had when the snapshot was taken.
+///
+/// Dereferencing an ArcLog reads the len each time, so two borrows can see
+/// different items. A snapshot holds its own reference on the allocation, so every
+/// borrow sees the same slice, and it can be sent to other threads while writers
+/// keep appending.
+pub struct ArcLogSnapshot<T, A: Allocator = Global> {
+    ptr: NonNull<ArcLogInner<T, A>>,
+    len: usize,
+    pd: PhantomData<ArcLogInner<T, A>>,
+}
+
+impl<T, A: Allocator> ArcLog<T, A> {
+    /// a view of the items this handle can see right now
+    pub fn snapshot(&self) -> ArcLogSnapshot<T, A> {
+        ArcLogSnapshot::new(self.ptr, self.len())
     }
 }
 
+impl<T, A: Allocator> ArcLogSnapshot<T, A> {
+    // len can't be more than the allocation has published
+    fn new(ptr: NonNull<ArcLogInner<T, A>>, len: usize) -> Self {
+        unsafe { (*addr_of!((*ptr.as_ptr()).header.count)).fetch_add(1, Relaxed) };
+        ArcLogSnapshot {
+            ptr,
+            len,
+            pd: PhantomData,
+        }
+    }
+
+    /// global index of the first item in the snapshot
+    pub fn start_index(&self) -> usize {
+        unsafe { (*self.ptr.as_ptr()).header.start_index }
+    }
+
+    /// global index one past the last item in the snapshot
+    pub fn end_index(&self) -> usize {
+        self.start_index() + self.len
+    }
+}
+
+unsafe impl<T: Send + Sync, A: Allocator + Send + Sync> Send for ArcLogSnapshot<T, A> {}
+unsafe impl<T: Send + Sync, A: Allocator + Send + Sync> Sync for ArcLogSnapshot<T, A> {}
+impl<T, A: Allocator> Unpin for ArcLogSnapshot<T, A> {}
+
+impl<T, A: Allocator> Clone for ArcLogSnapshot<T, A> {
+    fn clone(&self) -> Self {
+        ArcLogSnapshot::new(self.ptr, self.len)
+    }
+}
+
+impl<T, A: Allocator> Drop for ArcLogSnapshot<T, A> {
+    fn drop(&mut self) {
+        drop_ref(self.ptr);
+    }
+}
+
+impl<T, A: Allocator> Deref for ArcLogSnapshot<T, A> {
+    type Target = [T];
+
+    #[inline]
+    fn deref(&self) -> &[T] {
+        // SAFETY: the first len items were published before we read len, and never change
+        unsafe { slice::from_raw_parts(ArcLogInner::data_ptr(self.ptr), self.len) }
+    }
+}
+
+impl<T, A: Allocator, I: LogIndex + SliceIndex<[T]>> Index<I> for ArcLogSnapshot<T, A> {
+    type Output = I::Output;
+    #[inline]
+    fn index(&self, index: I) -> &Self::Output {
+        Index::index(&**self, index.rebase(self.start_index()))
+    }
+}
+
+impl<T: fmt::Debug, A: Allocator> fmt::Debug for ArcLogSnapshot<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ArcLogSnapshot")
+            .field("start_index", &self.start_index())
+            .field("len", &self.len)
+            .field("data", &&**self)
+            .finish()
+    }
+}
+
+/// Reads a log from a given index onward, following it into every new allocation.
+///
+/// The cursor holds a reference on one allocation at a time. Once it has read
+/// everything there and a forward exists, it moves on and releases the old one.
+pub struct ArcLogCursor<T, A: Allocator = Global> {
+    ptr: NonNull<ArcLogInner<T, A>>,
+    // global index of the next item to hand out
+    next: usize,
+    pd: PhantomData<ArcLogInner<T, A>>,
+}
+
+impl<T, A: Allocator> ArcLog<T, A> {
+    /// a cursor whose first item is the one at global `index`, which may not be pushed yet.
+    /// Panics if `index` was already compacted away
+    pub fn cursor_from(&self, index: usize) -> ArcLogCursor<T, A> {
+        rebase_index(index, self.start_index());
+        unsafe { (*addr_of!((*self.ptr.as_ptr()).header.count)).fetch_add(1, Relaxed) };
+        ArcLogCursor {
+            ptr: self.ptr,
+            next: index,
+            pd: PhantomData,
+        }
+    }
+}
+
+impl<T, A: Allocator> ArcLogCursor<T, A> {
+    /// global index of the item the cursor hands out next
+    pub fn next_index(&self) -> usize {
+        self.next
+    }
+
+    // hands out up to max items of the current allocation from next onward, moving
+    // to the forward first if everything here was already read
+    fn take(&mut self, max: usize) -> &[T] {
+        loop {
+            let header = unsafe { addr_of!((*self.ptr.as_ptr()).header) };
+            // acquire, so the items and the forward are visible
+            let raw_len = unsafe { (*header).len.load(Acquire) };
+            let local = self.next - unsafe { (*header).start_index };
+            let available = get_len(raw_len).saturating_sub(local);
+            if available > 0 || !has_forward(raw_len) {
+                let n = cmp::min(available, max);
+                if n == 0 {
+                    // local can be past the end of the allocation, don't offset by it
+                    return &[];
+                }
+                self.next += n;
+                let data = ArcLogInner::data_ptr(self.ptr);
+                // SAFETY: everything below len is initialized and never changes, and the
+                // allocation is ours until the next &mut call
+                return unsafe { slice::from_raw_parts(data.add(local), n) };
+            }
+            // SAFETY: the forward bit is only set after the forward is written
+            let p_forward = unsafe { (*header).forward.with(|f| (*f).unwrap_unchecked()) };
+            // the forward can't go away, our allocation holds a reference on it
+            unsafe { (*p_forward.as_ptr()).header.count.fetch_add(1, Relaxed) };
+            let old_ptr = mem::replace(&mut self.ptr, p_forward);
+            drop_ref(old_ptr);
+        }
+    }
+
+    /// the next item, if it has been pushed
+    pub fn next_available(&mut self) -> Option<&T> {
+        self.take(1).first()
+    }
+
+    /// every item that has been pushed since the last call, up to the end of the current
+    /// allocation. Empty if there is nothing new
+    pub fn next_batch(&mut self) -> &[T] {
+        self.take(usize::MAX)
+    }
+}
+
+unsafe impl<T: Send + Sync, A: Allocator + Send + Sync> Send for ArcLogCursor<T, A> {}
+impl<T, A: Allocator> Unpin for ArcLogCursor<T, A> {}
+
+impl<T, A: Allocator> Drop for ArcLogCursor<T, A> {
+    fn drop(&mut self) {
+        drop_ref(self.ptr);
+    }
+}
+
+impl<T, A: Allocator> fmt::Debug for ArcLogCursor<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ArcLogCursor")
+            .field("ptr", &self.ptr)
+            .field("next", &self.next)
+            .finish()
+    }
+}
+
+// state shared by every allocation of one log, owned by the last allocation in the chain
+struct ArcLogShared<G: ?Sized = dyn GrowthPolicy> {
+    // readers waiting for appends
+    wakers: WakerHeader<4>,
+    // global index that the next reallocation may drop everything before
+    compact_before: AtomicUsize,
+    // writers that parked instead of spinning on the lock
+    unlock_wakers: WakerHeader<4>,
+    // last so it can be unsized, the log only ever sees it as a dyn GrowthPolicy
+    policy: G,
+}
+
 // capacity and len should not change once we have a non-null forward pointer
 struct ArcLogInnerHeader<T, A: Allocator> {
     count: AtomicUsize,
@@ -273,6 +994,8 @@ struct ArcLogInnerHeader<T, A: Allocator> {
     // len will also use the top two bit to encode if writing and whether there
     // is a forwarding address
     len: AtomicUsize,
+    // global index of data[0], everything before it was compacted away
+    start_index: usize,
     // forward will be a null-ptr if there is no forward node
 
     // if alloc wasn't copy, we could move it when we created a forward
@@ -285,7 +1008,10 @@ struct ArcLogInnerHeader<T, A: Allocator> {
 
     // ideally this would be a thin pointer to ArcLogInner, but so far I cannot
     // find a way to express this. We assume we can cast back from InnerHeader to Inner
-    forward: Option<NonNull<ArcLogInner<T, A>>>,
+    // Written once by the lock holder before it sets the forward bit, so under loom
+    // every read is checked to come after that.
+    forward: crate::sync::UnsafeCell<Option<NonNull<ArcLogInner<T, A>>>>,
+    shared: NonNull<ArcLogShared>,
     alloc: A,
 }
 
@@ -294,476 +1020,575 @@ struct ArcLogInnerHeader<T, A: Allocator> {
 // that is appended to as part of the allocation. This also allows us to cast from a
 // InnerHeader back to Inner
 #[repr(C)]
-struct ArcLogInner<T, A: Allocator> {
+pub(crate) struct ArcLogInner<T, A: Allocator> {
     header: ArcLogInnerHeader<T, A>,
     data: [MaybeUninit<T>;0],
 }
 
 impl<T, A: Allocator + Clone> ArcLogInner<T, A> {
-    fn with_capacity(capacity: usize, alloc: A) -> NonNull<Self> {
-        let new_alloc = alloc
-            .allocate(Self::get_layout(capacity))
-            .expect("Error allocating")
-            .as_mut_ptr();
-        // SAFETY: The alloc was just made with T layout so this cast is safe
-        let ptr = unsafe { &mut *(new_alloc as *mut Self) };
-        ptr.header.forward = None;
-        ptr.header.count.store(1, Relaxed);
-        ptr.header.cap = if mem::size_of::<T>() == 0 {
-                isize::MAX as usize
-            } else {
-                capacity
-            };
-        ptr.header.alloc = alloc;
-        ptr.header.len.store(0, Release);
-        ptr.into()
+    fn try_with_capacity<G: GrowthPolicy + 'static>(
+        capacity: usize,
+        policy: G,
+        alloc: A,
+    ) -> Result<NonNull<Self>, TryReserveError> {
+        // a zero sized log can hold as many items as the len encoding can count
+        let cap = if mem::size_of::<T>() == 0 {
+            get_len(usize::MAX)
+        } else {
+            capacity
+        };
+        if has_forward_or_lock(cap) {
+            return Err(TryReserveErrorKind::CapacityOverflow.into());
+        }
+        let layout = Self::try_layout(cap)?;
+        let shared = ArcLogShared {
+            wakers: WakerHeader::new(),
+            compact_before: AtomicUsize::new(0),
+            unlock_wakers: WakerHeader::new(),
+            policy,
+        };
+        let shared: NonNull<ArcLogShared> = match Box::try_new_in(shared, &alloc) {
+            Ok(shared) => {
+                let shared: Box<ArcLogShared, &A> = shared;
+                Box::leak(shared).into()
+            }
+            Err(_) => {
+                return Err(TryReserveErrorKind::AllocError {
+                    layout: Layout::new::<ArcLogShared<G>>(),
+                }
+                .into())
+            }
+        };
+        let new_alloc = match alloc.allocate(layout) {
+            Ok(ptr) => ptr.as_mut_ptr() as *mut Self,
+            Err(_) => {
+                // SAFETY: it was just leaked from a Box in the same allocator
+                unsafe { drop(Box::from_raw_in(shared.as_ptr(), &alloc)) };
+                return Err(TryReserveErrorKind::AllocError { layout }.into());
+            }
+        };
+        // SAFETY: The alloc was just made with our layout, so it has room for the header
+        unsafe {
+            ptr::write(
+                addr_of_mut!((*new_alloc).header),
+                ArcLogInnerHeader {
+                    count: AtomicUsize::new(1),
+                    cap,
+                    len: AtomicUsize::new(0),
+                    start_index: 0,
+                    forward: crate::sync::UnsafeCell::new(None),
+                    shared,
+                    alloc,
+                },
+            );
+            Ok(NonNull::new_unchecked(new_alloc))
+        }
     }
 
-    #[instrument(skip(p_this))]
-    fn get_next_address(mut p_this: NonNull<Self>) -> NonNull<Self> {
-        let mut r_this =  unsafe{ p_this.as_ref()};
-        loop {
-            // this can be relaxed because we're only going to look at the forward
-            let _len = r_this.header.len.load(Acquire);
-            let new_this = unsafe { r_this.header.forward};
-            match new_this {
-                Some(fwrd) => {
-                    event!(Level::TRACE, "had to forward");
-                    // SAFETY: We check for null, so this must point to valid reference
-                    p_this = fwrd;
-                    r_this =  unsafe{ p_this.as_ref()};
+    #[inline]
+    fn moved(p_self: NonNull<Self>, p_this: NonNull<Self>) -> Option<NonNull<Self>> {
+        if p_this == p_self {
+            None
+        } else {
+            Some(p_this)
+        }
+    }
+
+    // Makes a new allocation with room for n_cap items and copies [from..len) of
+    // p_this into the start of it. Only the holder of the lock on p_this may call this,
+    // and it's still held on an error.
+    fn relocate(
+        p_this: NonNull<Self>,
+        from: usize,
+        len: usize,
+        n_cap: usize,
+    ) -> Result<NonNull<Self>, TryReserveError> {
+        event!(Level::TRACE, "new allocation has this size {:?}", n_cap);
+        let req_layout = Self::try_layout(n_cap)?;
+        let (alloc, shared, start_index) = unsafe {
+            let header = addr_of!((*p_this.as_ptr()).header);
+            ((*header).alloc.clone(), (*header).shared, (*header).start_index)
+        };
+        let ptr = match alloc.allocate(req_layout) {
+            Ok(ptr) => ptr,
+            Err(_) => return Err(TryReserveErrorKind::AllocError { layout: req_layout }.into()),
+        };
+        event!(Level::TRACE, "Old ptr: {:?} new_ptr: {:?}", p_this, ptr);
+        let new_mut_ptr = ptr.as_mut_ptr() as *mut Self;
+        unsafe {
+            ptr::write(
+                addr_of_mut!((*new_mut_ptr).header),
+                ArcLogInnerHeader {
+                    count: AtomicUsize::new(1),
+                    cap: n_cap,
+                    len: AtomicUsize::new(len - from),
+                    start_index: start_index + from,
+                    forward: crate::sync::UnsafeCell::new(None),
+                    shared,
+                    alloc,
                 },
-                None => {
-                    event!(Level::TRACE, "at end of forward change");
-                    break;
+            );
+            let p_new = NonNull::new_unchecked(new_mut_ptr);
+            ptr::copy_nonoverlapping(
+                Self::data_ptr(p_this).add(from),
+                Self::data_ptr(p_new),
+                len - from,
+            );
+            Ok(p_new)
+        }
+    }
+
+    // Must hold the lock on p_this, which has `len` items. Moves them to an allocation
+    // with room for `additional` more, leaving behind anything before the compaction
+    // point. p_this stays locked, on an error as well. Returns the new allocation and
+    // how many items were copied to it.
+    fn grow_locked(
+        p_this: NonNull<Self>,
+        len: usize,
+        additional: usize,
+    ) -> Result<(NonNull<Self>, usize), TryReserveError> {
+        let (cap, shared) = unsafe {
+            let header = addr_of!((*p_this.as_ptr()).header);
+            ((*header).cap, (*header).shared)
+        };
+        event!(Level::TRACE, "had to reallocate");
+        let from = Self::compacted_len(p_this, len);
+        let kept = len - from;
+        let required = kept + additional;
+        let grow_from = if from == 0 { cap } else { kept };
+        let policy = unsafe { &shared.as_ref().policy };
+        let n_cap = grown_capacity(policy, grow_from, required, mem::size_of::<T>());
+        let p_new = Self::relocate(p_this, from, len, n_cap)?;
+        Ok((p_new, kept))
+    }
+
+    // how many of the first `len` items of p_this are before the compaction point
+    // and can be left behind, they're dropped with this allocation
+    fn compacted_len(p_this: NonNull<Self>, len: usize) -> usize {
+        let (start_index, shared) = unsafe {
+            let header = addr_of!((*p_this.as_ptr()).header);
+            ((*header).start_index, (*header).shared)
+        };
+        let compact_before = unsafe { shared.as_ref().compact_before.load(Relaxed) };
+        cmp::min(compact_before.saturating_sub(start_index), len)
+    }
+
+    // Must hold the lock on p_this, which has `len` items. Moves what isn't compacted
+    // to an exact fit allocation, unless it already is one, and unlocks.
+    fn shrink_locked(p_this: NonNull<Self>, len: usize) -> Result<NonNull<Self>, TryReserveError> {
+        let cap = unsafe { (*p_this.as_ptr()).header.cap };
+        let from = Self::compacted_len(p_this, len);
+        if from == 0 && cap == len {
+            chain::unlock(p_this, len);
+            return Ok(p_this);
+        }
+        match Self::relocate(p_this, from, len, len - from) {
+            Ok(p_new) => {
+                chain::set_forward(p_this, len, p_new);
+                Ok(p_new)
+            }
+            Err(e) => {
+                chain::unlock(p_this, len);
+                Err(e)
+            }
+        }
+    }
+
+    // Must hold the lock on p_this, which has `len` items. Grows it if `additional`
+    // more don't fit and unlocks. Returns the allocation that is now the tail.
+    fn reserve_locked(
+        p_this: NonNull<Self>,
+        len: usize,
+        additional: usize,
+    ) -> Result<NonNull<Self>, TryReserveError> {
+        let cap = unsafe { (*p_this.as_ptr()).header.cap };
+        match len.checked_add(additional) {
+            Some(new_len) if !has_forward_or_lock(new_len) => {
+                if new_len <= cap {
+                    chain::unlock(p_this, len);
+                    return Ok(p_this);
                 }
             }
+            _ => {
+                chain::unlock(p_this, len);
+                return Err(TryReserveErrorKind::CapacityOverflow.into());
+            }
+        }
+        match Self::grow_locked(p_this, len, additional) {
+            Ok((p_new, _)) => {
+                chain::set_forward(p_this, len, p_new);
+                Ok(p_new)
+            }
+            Err(e) => {
+                chain::unlock(p_this, len);
+                Err(e)
+            }
+        }
+    }
+
+    // Must hold the lock on p_this, which has `len` items. Writes the new items in place,
+    // or moves everything to a bigger allocation and forwards to it, then unlocks.
+    // Returns the index of the first new item and the allocation it ended up in.
+    // On an error nothing was written and p_this is unlocked.
+    fn write_locked(
+        p_this: NonNull<Self>,
+        len: usize,
+        data_ptr: *const T,
+        count: usize,
+    ) -> Result<(usize, NonNull<Self>), TryReserveError> {
+        let (cap, start_index) = unsafe {
+            let header = addr_of!((*p_this.as_ptr()).header);
+            ((*header).cap, (*header).start_index)
+        };
+        // this should still respect boundary set by len
+        let new_len = match len.checked_add(count) {
+            Some(new_len) if !has_forward_or_lock(new_len) => new_len,
+            _ => {
+                chain::unlock(p_this, len);
+                return Err(TryReserveErrorKind::CapacityOverflow.into());
+            }
+        };
+        if new_len <= cap {
+            // we can just add our data an and update the len
+            //https://github.com/rust-lang/unsafe-code-guidelines/issues/256
+            unsafe { ptr::copy_nonoverlapping(data_ptr, Self::data_ptr(p_this).add(len), count) };
+            chain::unlock(p_this, new_len);
+            return Ok((start_index + len, p_this));
+        }
+        let (p_new, kept) = match Self::grow_locked(p_this, len, count) {
+            Ok(grown) => grown,
+            Err(e) => {
+                chain::unlock(p_this, len);
+                return Err(e);
+            }
+        };
+        event!(Level::TRACE, "start writing new data to new allocation");
+        unsafe {
+            ptr::copy_nonoverlapping(data_ptr, Self::data_ptr(p_new).add(kept), count);
+            (*p_new.as_ptr()).header.len.store(kept + count, Relaxed);
         }
-        p_this
+        chain::set_forward(p_this, len, p_new);
+        Ok((start_index + len, p_new))
     }
-    // #[inline(always)]
-    // pub(crate) unsafe fn data(this: NonNull<Self>) -> (NonNull<T>, usize) {
-    //     let size = this.as_ref().header.;
-    //     let tptr = this.as_ptr();
-    //     let daddr = core::ptr::addr_of_mut!((*tptr).data);
-    //     let nn = NonNull::new_unchecked(daddr.cast::<T>());
-    //     (nn, size)
-    // }
 
+    // Zero sized items are never written or moved, so a zero sized log never locks or
+    // forwards and a push is just a bump of len. An unconditional push of a modest count
+    // takes its index as a ticket from one fetch_add, everything else (a push against
+    // ref_index, or one close to the end of the len encoding) goes through a compare exchange.
+    fn alloc_zero_sized(
+        p_self: NonNull<Self>,
+        count: usize,
+        ref_index: usize,
+        mut pred: Option<PushPredicate<T>>,
+        spin: bool,
+    ) -> Result<isize, TryReserveError> {
+        event!(Level::TRACE, "is zero sized");
+        let len_ref = unsafe { &(*p_self.as_ptr()).header.len };
+        if ref_index == usize::MAX
+            && pred.is_none()
+            && count <= ZST_TICKET_MAX
+            && len_ref.load(Relaxed) <= ZST_TICKET_BELOW
+        {
+            // every thread has at most one of these between its check and its add, so
+            // len can't get from below ZST_TICKET_BELOW to the lock bit before a check fails
+            let ticket = len_ref.fetch_add(count, Release);
+            debug_assert!(!has_forward_or_lock(ticket + count));
+            Self::wake_readers(p_self);
+            return Ok(ticket as isize);
+        }
+        // a zero sized log never moves, so its start_index stays 0
+        loop {
+            let len = len_ref.load(Relaxed);
+            debug_assert!(!has_forward_or_lock(len));
+            if get_len(len) > ref_index {
+                return Ok(-1);
+            }
+            // without a lock the predicate has to run again whenever another push gets in
+            if let Some(pred) = pred.as_mut() {
+                let items = unsafe { slice::from_raw_parts(Self::data_ptr(p_self), len) };
+                if !pred(items) {
+                    return Ok(-1);
+                }
+            }
+            let new_len = match len.checked_add(count) {
+                Some(new_len) if !has_forward_or_lock(new_len) => new_len,
+                _ => return Err(TryReserveErrorKind::CapacityOverflow.into()),
+            };
+            // not weak, a one shot push should only fail if another push got in
+            match len_ref.compare_exchange(len, new_len, Release, Relaxed) {
+                Ok(_) => {
+                    Self::wake_readers(p_self);
+                    return Ok(len as isize);
+                }
+                Err(_) if !spin => return Ok(-1),
+                Err(_) => hint::spin_loop(),
+            }
+        }
+    }
 
     #[instrument(skip(p_self))]
     fn alloc_items_one_shot(
-        mut p_self: NonNull<Self>,
+        p_self: NonNull<Self>,
         data_ptr: *const T,
         count: usize,
         ref_index: usize,
-    ) -> (isize, Option<NonNull<Self>>) {
+    ) -> (Result<isize, TryReserveError>, Option<NonNull<Self>>) {
         event!(Level::TRACE, "enter alloc items one shot");
         debug_assert!(count > 0);
-        let size_of_t = mem::size_of::<T>();
-        
-        if size_of_t == 0 {
-            event!(Level::TRACE, "is zero sized");
-            let r_self = unsafe {p_self.as_ref()};
-            // TODO: in the zero size case, this can probably be relaxed
-            let len = r_self.header.len.load(Acquire);
-            if len > ref_index {
-                return (-1, None);
-            } else {
-                // TODO: Find out what nightly Vec with alloc does here
-                //let not_len = !len;
-                let new_len = len.checked_add(count).expect("capacity overflow");
-                match r_self
-                    .header
-                    .len
-                    .compare_exchange(len, new_len, Relaxed, Relaxed)
-                {
-                    Ok(_old_claim_val) => {
-                        //self.header.len.store(new_len, Release);
-                        return (len as isize, None);
-                    }
-                    Err(_old_claim_val) => {
-                        return (-1, None);
-                    }
-                }
-            }
-        } else {
-            event!(Level::TRACE, "is not zero sized");
-            let mut p_this = Self::get_next_address(p_self);
-            let r_this = unsafe {p_this.as_ref()};
-            event!(Level::TRACE, "got new address");
-            // we reached the end of the forwarding so we have have to set an acquire barrier so that
-            // everything is in sync
-            let raw_len = r_this.header.len.load(Acquire);
-            let len  = get_len(raw_len);
-            if has_forward_or_lock(raw_len) || len > ref_index {
-                return if p_this == p_self {
-                    (-1, None)
-                } else {
-                    (-1, Some(p_this.into()))
-                };
-            }
-            //let negative_len = !len;
-            // this should still respect boundary set by len
-            let new_len = len.checked_add(count).expect("capacity overflow");
-            if has_forward_or_lock(new_len) {
-                panic!("capacity overflow");
-            } 
-            let cap = r_this.header.cap;
-            if new_len > cap {
-                event!(Level::TRACE, "had to reallocate");
-                // This guarantees exponential growth. The doubling cannot overflow
-                // because `cap <= isize::MAX` and the type of `cap` is `usize`.
-                let n_cap = cmp::max(cap * 2, new_len);
-
-                let elem_size = size_of_t;
-                let min_non_zero_cap = if elem_size == 1 {
-                    8
-                } else if elem_size <= 1024 {
-                    4
-                } else {
-                    1
-                };
-                let n_cap = cmp::max(min_non_zero_cap, n_cap);
-                event!(Level::TRACE, "new allocation has this size {:?}", n_cap);
-                let req_layout = Self::get_layout(n_cap);
-                event!(Level::TRACE, "got new layout");
-                let req_size = req_layout.size();
-                let locked_len = lock_len(len);
-                match r_this
-                    .header
-                    .len
-                    .compare_exchange(len, locked_len, Relaxed, Relaxed)
-                {
-                    Ok(_old_claim_val) => {
-                        event!(Level::TRACE, "got claim to add entries");
-                        let ptr = r_this
-                            .header
-                            .alloc
-                            .allocate(req_layout)
-                            .unwrap_or_else(|_| handle_alloc_error(req_layout));
-                        event!(Level::TRACE, "got allocation");
-                        let new_mut_ref = unsafe {
-                            let new_alloc_len = ptr.as_ref().len();
-                            event!(
-                                Level::TRACE,
-                                "Old ptr: {:?} new_ptr: {:?} with len: {:?}",
-                                p_this,
-                                ptr,
-                                new_alloc_len
-                            );
-                            assert_eq!(new_alloc_len, req_size);
-
-                            let new_mut_ptr = ptr.as_mut_ptr() as *mut Self;
-
-                            //ptr::copy_nonoverlapping(p_this.as_ptr(), new_mut_ptr, 1);
-                            ptr::copy_nonoverlapping( //(ptr::addr_of_mut!((*new_mut_ptr).data) as *mut T).offset(len as isize)
-                                (*p_self.as_ptr()).data.as_ptr(),
-                                (*new_mut_ptr).data.as_mut_ptr(),
-                                len,
-                            );
-                            event!(Level::TRACE, "start writing new data to new allocation");
-                            ptr::copy_nonoverlapping(
-                                data_ptr,
-                                ((*new_mut_ptr).data.as_mut_ptr() as *mut T).offset(len as isize),
-                                count,
-                            );
-                            &mut *new_mut_ptr
-                        };
-                        event!(Level::TRACE, "updating automics");
-                        new_mut_ref.header.count.store(1, Relaxed);
-                        new_mut_ref.header.cap = n_cap;
-                        new_mut_ref.header.len.store(new_len, Relaxed);
-                        new_mut_ref.header.alloc = unsafe { (*p_self.as_ptr()).header.alloc.clone() };
-                        new_mut_ref.header.forward = None;
-                        // the data has to be ready once we update the forward ptr,
-                        // so this must be a release
-                        let new_nn_ptr : NonNull<_> = new_mut_ref.into();
-                        unsafe {(*p_self.as_ptr()).header.forward = Some(new_nn_ptr);}
-                        let forward_len = add_forward_to_len(len);
-                        // this should also be release, otherwise it could be moved before the forward
-                        // and the forward must be seen by the next write
-                        unsafe { (*p_self.as_ptr()).header.len.store(forward_len, Release) };
-                        return (len as isize, Some(new_nn_ptr));
-                    }
-                    Err(_old_claim_val) => {
-                        event!(Level::TRACE, "couldn't get claim");
-                        return if p_this == p_self {
-                            (-1, None)
-                        } else {
-                            (-1, Some(p_this))
-                        };
-                    }
-                }
-            } else {
-                let locked_len = lock_len(len);
-                match r_this
-                    .header
-                    .len
-                    .compare_exchange(len, locked_len, Relaxed, Relaxed)
-                {
-                    Ok(_old_claim_val) => {                       
-                        // we can just add our data an and update the len
-                        unsafe {
-                            ptr::copy_nonoverlapping(
-                                data_ptr,
-                                ((*p_this.as_ptr()).data.as_mut_ptr() as *mut T).offset(len as isize),
-                                count,
-                            );
-                        }
-                        r_this.header.len.store(new_len, Release);
-                        if p_this == p_self {
-                            return (len as isize, None);
-                        } else {
-                            return (len as isize, Some(p_this));
-                        }
-                    }
-                    Err(_old_claim_val) => {
-                        return if p_this == p_self {
-                            (-1, None)
-                        } else {
-                            (-1, Some(p_this))
-                        };
-                    }
-                }
+        if mem::size_of::<T>() == 0 {
+            return (Self::alloc_zero_sized(p_self, count, ref_index, None, false), None);
+        }
+        let (p_this, len) = match chain::lock_tail(p_self, None) {
+            Ok(locked) => locked,
+            Err(p_this) => {
+                event!(Level::TRACE, "couldn't get claim");
+                return (Ok(-1), Self::moved(p_self, p_this));
             }
+        };
+        if unsafe { (*p_this.as_ptr()).header.start_index } + len > ref_index {
+            chain::unlock(p_this, len);
+            return (Ok(-1), Self::moved(p_self, p_this));
+        }
+        match Self::write_locked(p_this, len, data_ptr, count) {
+            Ok((index, p_this)) => (Ok(index as isize), Self::moved(p_self, p_this)),
+            Err(e) => (Err(e), Self::moved(p_self, p_this)),
         }
     }
 
-    #[instrument(skip(p_self))]
-    fn alloc_items(
+    #[instrument(skip(p_self, backoff))]
+    pub(crate) fn alloc_items(
         p_self: NonNull<Self>,
         data_ptr: *const T,
         count: usize,
-        ref_index: isize,
-    ) -> (isize, Option<NonNull<Self>>) {
+        ref_index: usize,
+        backoff: &mut dyn Backoff,
+    ) -> (Result<isize, TryReserveError>, Option<NonNull<Self>>) {
         event!(Level::TRACE, "enter alloc items");
         debug_assert!(count > 0);
-        let size_of_t = mem::size_of::<T>();
-        if size_of_t == 0 {
-            event!(Level::TRACE, "is zero sized");
-            let r_self = unsafe { p_self.as_ref() };
-            // if ths size of T is zero, there will never be a need to forward so we can do a much tighter loop
-            loop {
-                let mut len = r_self.header.len.load(Acquire);
-                if len > ref_index as usize {
-                    return (-1, None);
-                }
-                // this should still respect boundary set by len
-                let new_len = len.checked_add(count).expect("Too many entries");
-                match r_self
-                    .header
-                    .len
-                    .compare_exchange_weak(len, new_len, Relaxed, Relaxed)
-                {
-                    Ok(_old_claim_val) => {
-                        r_self.header.len.store(new_len, Release);
-                        return (len as isize, None);
-                    }
-                    Err(_old_claim_val) => {}
-                }
-            }
-        } else {
-            let mut p_this = p_self;
-            let mut raw_len;
-            event!(Level::TRACE, "is not zero sized");
-            'outer: loop {
-                raw_len = unsafe {(*p_this.as_ptr()).header.len.load(Acquire)};
-                event!(Level::TRACE, "raw_len is {}", raw_len);
-                if has_forward(raw_len){
-                    event!(Level::TRACE, "has forward");
-                    p_this = unsafe { (*p_this.as_ptr()).header.forward.unwrap_unchecked() };
-                    continue;
-                } else if is_locked(raw_len) {
-                    event!(Level::TRACE, "locked, waiting for unlock");
-                    // might need to sleep
-                    continue;                    
-                } else {
-                    event!{Level::TRACE, "entering inner loop"};
-                    loop {
-                        event!{Level::TRACE, "inner loop"};
-                        match  unsafe{(*p_this.as_ptr()).header.len.compare_exchange(raw_len, lock_len(raw_len), Acquire, Acquire)} {
-                            Ok(_) => {
-                                break 'outer
-                            },
-                            Err(new_value) => {
-                                event!(Level::TRACE, "len changed, new value is {}", new_value);
-                                if has_forward(new_value){
-                                    p_this = unsafe {  (*p_this.as_ptr()).header.forward.unwrap_unchecked() };
-                                    continue 'outer;
-                                } else if is_locked(new_value) {
-                                    continue 'outer;
-                                } else {
-                                    raw_len = new_value;
-                                    continue;
-                                }
-                            }
-                        }
-                    }
+        if mem::size_of::<T>() == 0 {
+            return (Self::alloc_zero_sized(p_self, count, ref_index, None, true), None);
+        }
+        let (p_this, len) = match chain::lock_tail(p_self, Some(backoff)) {
+            Ok(locked) => locked,
+            Err(_) => unreachable!(),
+        };
+        if unsafe { (*p_this.as_ptr()).header.start_index } + len > ref_index {
+            chain::unlock(p_this, len);
+            return (Ok(-1), Self::moved(p_self, p_this));
+        }
+        match Self::write_locked(p_this, len, data_ptr, count) {
+            Ok((index, p_this)) => (Ok(index as isize), Self::moved(p_self, p_this)),
+            Err(e) => (Err(e), Self::moved(p_self, p_this)),
+        }
+    }
+
+    // Like alloc_items, but instead of comparing to an index it runs `pred` on the items
+    // of the tail while holding its lock, and only writes if that returns true
+    #[instrument(skip(p_self, pred, backoff))]
+    pub(crate) fn alloc_items_if(
+        p_self: NonNull<Self>,
+        data_ptr: *const T,
+        count: usize,
+        pred: PushPredicate<T>,
+        backoff: &mut dyn Backoff,
+    ) -> (Result<isize, TryReserveError>, Option<NonNull<Self>>) {
+        event!(Level::TRACE, "enter alloc items if");
+        debug_assert!(count > 0);
+        if mem::size_of::<T>() == 0 {
+            return (Self::alloc_zero_sized(p_self, count, usize::MAX, Some(pred), true), None);
+        }
+        let (p_this, len) = match chain::lock_tail(p_self, Some(backoff)) {
+            Ok(locked) => locked,
+            Err(_) => unreachable!(),
+        };
+        // the first len items are written and nobody else can touch them while we hold the lock
+        let items = unsafe { slice::from_raw_parts(Self::data_ptr(p_this), len) };
+        let guard = UnlockGuard { p_this, len };
+        let keep = pred(items);
+        mem::forget(guard);
+        if !keep {
+            chain::unlock(p_this, len);
+            return (Ok(-1), Self::moved(p_self, p_this));
+        }
+        match Self::write_locked(p_this, len, data_ptr, count) {
+            Ok((index, p_this)) => (Ok(index as isize), Self::moved(p_self, p_this)),
+            Err(e) => (Err(e), Self::moved(p_self, p_this)),
+        }
+    }
+}
+
+// decides from the items already in the log whether a conditional push goes ahead
+type PushPredicate<'a, T> = &'a mut dyn FnMut(&[T]) -> bool;
+
+// unlocks the allocation if a predicate run under its lock panics, nothing was written yet
+struct UnlockGuard<T, A: Allocator + Clone> {
+    p_this: NonNull<ArcLogInner<T, A>>,
+    len: usize,
+}
+
+impl<T, A: Allocator + Clone> Drop for UnlockGuard<T, A> {
+    fn drop(&mut self) {
+        chain::unlock(self.p_this, self.len);
+    }
+}
+
+impl<T, A: Allocator> ArcLog<T, A> {
+    // hands the reference this handle holds over to the caller
+    pub(crate) fn into_raw(self) -> NonNull<ArcLogInner<T, A>> {
+        ManuallyDrop::new(self).ptr
+    }
+}
+
+unsafe impl<T, A: Allocator> Node for ArcLogInner<T, A> {
+    unsafe fn len<'a>(p_this: NonNull<Self>) -> &'a AtomicUsize {
+        &(*p_this.as_ptr()).header.len
+    }
+
+    unsafe fn count<'a>(p_this: NonNull<Self>) -> &'a AtomicUsize {
+        &(*p_this.as_ptr()).header.count
+    }
+
+    unsafe fn forward(p_this: NonNull<Self>) -> NonNull<Self> {
+        (*p_this.as_ptr()).header.forward.with(|f| (*f).unwrap_unchecked())
+    }
+
+    unsafe fn write_forward(p_this: NonNull<Self>, p_new: NonNull<Self>) {
+        (*p_this.as_ptr()).header.forward.with_mut(|f| *f = Some(p_new));
+    }
+
+    unsafe fn unlock_wakers<'a>(p_this: NonNull<Self>) -> &'a WakerHeader<4> {
+        &(*p_this.as_ptr()).header.shared.as_ref().unlock_wakers
+    }
+
+    // readers waiting for appends, most unlocks come with new items or a forward
+    unsafe fn wake_fenced(p_this: NonNull<Self>) {
+        (*p_this.as_ptr()).header.shared.as_ref().wakers.wake_all_fenced();
+    }
+
+    unsafe fn release(ptr: NonNull<Self>) -> Option<NonNull<Self>> {
+        let raw_len = (*ptr.as_ptr()).header.len.load(Acquire);
+        let forward_ptr = (*ptr.as_ptr()).header.forward.with(|f| *f);
+        // the allocator lives in the block it's about to free, so move it out first.
+        // It's dropped once the block is gone, like every other field of the header
+        let alloc = ptr::read(&(*ptr.as_ptr()).header.alloc);
+        match forward_ptr {
+            Some(f_ptr) => {
+                // the forward is responsible for dropping every item it copied, but anything
+                // that was compacted away before it only lives here
+                let owned = cmp::min(
+                    (*f_ptr.as_ptr()).header.start_index - (*ptr.as_ptr()).header.start_index,
+                    get_len(raw_len),
+                );
+                if owned > 0 {
+                    event!(Level::TRACE, "dropping {:?} compacted items", owned);
+                    ptr::drop_in_place(ptr::slice_from_raw_parts_mut(Self::data_ptr(ptr), owned));
                 }
             }
-            event!(Level::TRACE, "got new lock");
-                // we reached the end of the forwarding so we have have to set an acquire barrier so that
-                // everything is in sync
-            let len  = raw_len;
-                if len as isize > ref_index {
-                    return if p_this == p_self {
-                        (-1, None)
-                    } else {
-                        (-1, Some(p_this))
-                    };
-                }
-                // this should still respect boundary set by len
-                let new_len = len.checked_add(count).expect("Too many entries");
-                let cap =  unsafe{(*p_this.as_ptr()).header.cap};
-
-                if new_len > cap {
-                    event!(Level::TRACE, "had to reallocate");
-                    // This guarantees exponential growth. The doubling cannot overflow
-                    // because `cap <= isize::MAX` and the type of `cap` is `usize`.
-                    let n_cap = cmp::max(cap * 2, new_len);
-
-                    let elem_size = size_of_t;
-                    let min_non_zero_cap = if elem_size == 1 {
-                        8
-                    } else if elem_size <= 1024 {
-                        4
-                    } else {
-                        1
-                    };
-                    let n_cap = cmp::max(min_non_zero_cap, n_cap);
-                    event!(Level::TRACE, "new allocation has this size {:?}", n_cap);
-                    let req_layout = Self::get_layout(n_cap);
-                    event!(Level::TRACE, "got new layout");
-                    let req_size = req_layout.size();
-                    let r_self = unsafe {p_self.as_ref()};
-                    let ptr = r_self
-                                .header
-                                .alloc
-                                .allocate(req_layout)
-                                .unwrap_or_else(|_| handle_alloc_error(req_layout));
-                    event!(Level::TRACE, "got allocation");
-                    let new_mut_ref = unsafe {
-                                let new_alloc_len = ptr.as_ref().len();
-                                event!(
-                                    Level::TRACE,
-                                    "Old ptr: {:?} new_ptr: {:?} with len: {:?}",
-                                    p_this,
-                                    ptr,
-                                    new_alloc_len
-                                );
-                                assert_eq!(new_alloc_len, req_size);
-
-                                let new_mut_ptr = ptr.as_mut_ptr() as *mut Self;
-
-                                //ptr::copy_nonoverlapping(this, new_mut_ptr, 1);
-                                ptr::copy_nonoverlapping(
-                                    ptr::addr_of!((*p_this.as_ptr()).data) as *const T,
-                                    ptr::addr_of_mut!((*new_mut_ptr).data) as *mut T,
-                                    len as usize,
-                                );
-                                event!(Level::TRACE, "start writing new data to new allocation");
-                                ptr::copy_nonoverlapping(
-                                    data_ptr,
-                                    (ptr::addr_of_mut!((*new_mut_ptr).data) as *mut T).offset(len as isize),
-                                    count,
-                                );
-                                &mut *new_mut_ptr
-                            };
-                            event!(Level::TRACE, "updating automics");
-                            new_mut_ref.header.count.store(1, Relaxed);
-                            new_mut_ref.header.cap = n_cap;
-                            new_mut_ref.header.len.store(new_len, Relaxed);
-                            new_mut_ref.header.alloc = r_self.header.alloc.clone();
-                            new_mut_ref.header.forward = None;
-                            let new_mut_ref: NonNull<_> = unsafe { NonNull::new_unchecked(ptr.as_mut_ptr() as *mut Self)}; //new_mut_ref.into();
-                            // the data has to be ready once we update the forward ptr,
-                            // so this must be a release
-                           
-                            unsafe { (*p_this.as_ptr()).header.forward = Some(new_mut_ref); }
-                            let r_this2 = unsafe {p_this.as_ref()};
-                            // this should also be release, otherwise it could be moved before the forward
-                            // and the forward must be seen by the next write
-                            r_this2.header.len.store( add_forward_to_len(len), Release);
-                            return (len as isize, Some(new_mut_ref));                
-                } else {
-                    
-                            // we can just add our data an and update the len
-                            unsafe {
-                                // ptr::copy_nonoverlapping(
-                                //     data_ptr,
-                                //     (ptr::addr_of_mut!(this.data) as *mut T).offset(len as isize),
-                                //     count,
-                                //);
-
-                                //https://github.com/rust-lang/unsafe-code-guidelines/issues/256
-                                ptr::copy_nonoverlapping(
-                                    data_ptr,
-                                    (ptr::addr_of_mut!((*p_this.as_ptr()).data) as *mut T).offset(len as isize),
-                                    count,
-                                );
-                            }
-                            unsafe{(*p_this.as_ptr()).header.len.store(new_len, Release)};
-                            if p_this == p_self {
-                                return (len as isize, None);
-                            } else {
-                                return (len as isize, Some(p_this));
-                            }
-                       
+            None => {
+                let len_to_drop = get_len(raw_len);
+                event!(
+                    Level::TRACE,
+                    "forward pointer is null, with {:?} items to drop",
+                    len_to_drop
+                );
+                ptr::drop_in_place(ptr::slice_from_raw_parts_mut(Self::data_ptr(ptr), len_to_drop));
+                // the last allocation in the chain owns the shared block
+                let shared = (*ptr.as_ptr()).header.shared;
+                drop(Box::from_raw_in(shared.as_ptr(), &alloc));
             }
         }
+        event!(Level::TRACE, "calling dealloc");
+        // SAFETY: We are the last reference so we need to deallocate
+        let layout = Self::get_layout((*ptr.as_ptr()).header.cap);
+        alloc.deallocate(NonNull::new_unchecked(ptr.as_ptr() as *mut u8), layout);
+        forward_ptr
     }
 }
 
 impl<T, A: Allocator> ArcLogInner<T, A> {
+    // Offset from the allocation pointer itself instead of taken through the zero
+    // length data field, so it keeps the provenance of the whole allocation
+    #[inline]
+    pub(crate) fn data_ptr(p_this: NonNull<Self>) -> *mut T {
+        unsafe { p_this.as_ptr().cast::<u8>().add(mem::offset_of!(Self, data)).cast::<T>() }
+    }
+
+    // the published items of p_this, which has to outlive 'a
+    pub(crate) unsafe fn items<'a>(p_this: NonNull<Self>) -> &'a [T] {
+        let len = get_len((*p_this.as_ptr()).header.len.load(Acquire));
+        slice::from_raw_parts(Self::data_ptr(p_this), len)
+    }
+
+    pub(crate) fn start_index(p_this: NonNull<Self>) -> usize {
+        unsafe { (*p_this.as_ptr()).header.start_index }
+    }
+
+    // must come after the Release store to len, so a woken reader sees the new items
+    fn wake_readers(p_this: NonNull<Self>) {
+        unsafe { (*p_this.as_ptr()).header.shared.as_ref().wakers.wake_all() };
+    }
 
+    // only for caps that were already allocated, so it can't fail
     fn get_layout(data_cap: usize) -> Layout {
-        unsafe {
-            let align = mem::align_of::<Self>();
-            let size = mem::size_of::<Self>();
-            let layout = Layout::from_size_align_unchecked(size, align);
-            let layout_data = match Layout::array::<T>(data_cap) {
-                Ok(layout) => layout,
-                _ => panic!("Bad array layout"),
-            };
-            let layout = match layout.extend(layout_data) {
-                Ok((layout, _)) => layout,
-                _ => panic!("Bad layout"),
-            };
-            event!(
-                Level::TRACE,
-                "layout dims, data_cap: {:?}, size: {:?}, align: {:?}",
-                data_cap,
-                layout.size(),
-                layout.align()
-            );
-            layout //.pad_to_align() not sure if this is needed?
-                   // if it is, might have to be accounted for on ptr copy
+        match Self::try_layout(data_cap) {
+            Ok(layout) => layout,
+            _ => panic!("Bad layout"),
         }
     }
+
+    fn try_layout(data_cap: usize) -> Result<Layout, TryReserveError> {
+        let layout = Layout::new::<Self>();
+        let layout_data = Layout::array::<T>(data_cap)?;
+        let (layout, _) = layout.extend(layout_data)?;
+        event!(
+            Level::TRACE,
+            "layout dims, data_cap: {:?}, size: {:?}, align: {:?}",
+            data_cap,
+            layout.size(),
+            layout.align()
+        );
+        Ok(layout) //.pad_to_align() not sure if this is needed?
+                   // if it is, might have to be accounted for on ptr copy
+    }
 }
 
-const fn has_forward(val: usize) -> bool {
+// same smallest allocation as Vec
+pub(crate) const fn min_non_zero_cap(elem_size: usize) -> usize {
+    if elem_size == 1 {
+        8
+    } else if elem_size <= 1024 {
+        4
+    } else {
+        1
+    }
+}
+
+// bounds for the fetch_add path of zero sized pushes, the gap between the two leaves
+// room for a huge number of racing pushes before the lock bit
+const ZST_TICKET_MAX: usize = 1 << (usize::BITS / 2 - 2);
+const ZST_TICKET_BELOW: usize = usize::MAX >> 3;
+
+pub(crate) const fn has_forward(val: usize) -> bool {
     (val | (usize::MAX >> 1)) == usize::MAX
 }
 
-const fn is_locked(val: usize) -> bool {
+pub(crate) const fn is_locked(val: usize) -> bool {
     ((val << 1) | (usize::MAX >> 1)) == usize::MAX
 }
 
-const fn get_len(val: usize) -> usize {
+pub(crate) const fn get_len(val: usize) -> usize {
      val & (usize::MAX >> 2)
 }
 
-const fn lock_len(val: usize) -> usize {
+pub(crate) const fn lock_len(val: usize) -> usize {
     val | (!(usize::MAX >> 1) >> 1)
 }
 
-const fn add_forward_to_len(val: usize) -> usize {
+pub(crate) const fn add_forward_to_len(val: usize) -> usize {
     val | (!(usize::MAX >> 1))
 }
 
-const fn has_forward_or_lock(val: usize) -> bool {
-    val & (usize::MAX >> 2) == val
+pub(crate) const fn has_forward_or_lock(val: usize) -> bool {
+    val & (usize::MAX >> 2) != val
 }
 
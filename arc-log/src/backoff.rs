@@ -0,0 +1,179 @@
+use crate::sync::hint;
+use crate::sync::{AtomicUsize, Ordering::*};
+
+#[cfg(feature = "std")]
+use alloc::sync::Arc;
+#[cfg(feature = "std")]
+use alloc::task::Wake;
+#[cfg(feature = "std")]
+use core::task::Waker;
+#[cfg(feature = "std")]
+use std::thread::{self, Thread};
+
+use crate::arc_log::is_locked;
+use crate::waker_list::WakerHeader;
+
+/// What a writer does while another writer holds the lock on the log.
+///
+/// A fresh value is used for every push, so a strategy can keep state across the
+/// waits of one push (like how long it has been waiting) without resetting it.
+pub trait Backoff {
+    /// Called each time the writer finds the log locked. It's fine to return while
+    /// the lock is still held, the writer just checks again and calls this again.
+    fn snooze(&mut self, lock: &LockWaiter<'_>);
+}
+
+/// The lock a writer is waiting on, handed to [`Backoff::snooze`]
+pub struct LockWaiter<'a> {
+    len: &'a AtomicUsize,
+    unlock_wakers: &'a WakerHeader<4>,
+}
+
+impl<'a> LockWaiter<'a> {
+    pub(crate) fn new(len: &'a AtomicUsize, unlock_wakers: &'a WakerHeader<4>) -> Self {
+        LockWaiter { len, unlock_wakers }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        is_locked(self.len.load(Relaxed))
+    }
+
+    /// Wakers registered here are woken every time a writer releases the lock. Check
+    /// `is_locked` again after registering, the release may have happened in between.
+    pub fn unlock_wakers(&self) -> &WakerHeader<4> {
+        self.unlock_wakers
+    }
+}
+
+/// Spins in place until the lock is released, what push_spin does
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Spin;
+
+impl Backoff for Spin {
+    #[inline]
+    fn snooze(&mut self, _lock: &LockWaiter<'_>) {
+        hint::spin_loop();
+    }
+}
+
+/// Doubles the number of spins between checks each time, up to `2^max_step`
+#[derive(Clone, Copy, Debug)]
+pub struct Exponential {
+    step: u32,
+    max_step: u32,
+}
+
+impl Exponential {
+    /// `max_step` is capped at 31, past that the spin count wouldn't fit a u32
+    pub fn new(max_step: u32) -> Self {
+        Exponential {
+            step: 0,
+            max_step: max_step.min(u32::BITS - 1),
+        }
+    }
+}
+
+impl Default for Exponential {
+    fn default() -> Self {
+        Self::new(10)
+    }
+}
+
+impl Backoff for Exponential {
+    fn snooze(&mut self, _lock: &LockWaiter<'_>) {
+        for _ in 0..1u32 << self.step {
+            hint::spin_loop();
+        }
+        if self.step < self.max_step {
+            self.step += 1;
+        }
+    }
+}
+
+/// Spins `spins` times, then yields the thread on every check after that, so a
+/// descheduled lock holder can get back on a core
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug)]
+pub struct SpinThenYield {
+    spins: u32,
+    remaining: u32,
+}
+
+#[cfg(feature = "std")]
+impl SpinThenYield {
+    pub fn new(spins: u32) -> Self {
+        SpinThenYield {
+            spins,
+            remaining: spins,
+        }
+    }
+
+    pub fn spins(&self) -> u32 {
+        self.spins
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for SpinThenYield {
+    fn default() -> Self {
+        Self::new(64)
+    }
+}
+
+#[cfg(feature = "std")]
+impl Backoff for SpinThenYield {
+    fn snooze(&mut self, _lock: &LockWaiter<'_>) {
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            hint::spin_loop();
+        } else {
+            thread::yield_now();
+        }
+    }
+}
+
+/// Parks the thread until the writer holding the lock releases it. Costs a
+/// register and a park per wait, so it's for locks held a long time, like
+/// while a large log is copied to a new allocation.
+#[cfg(feature = "std")]
+#[derive(Default)]
+pub struct Park {
+    waker: Option<Waker>,
+}
+
+#[cfg(feature = "std")]
+impl Park {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(feature = "std")]
+struct Unpark(Thread);
+
+#[cfg(feature = "std")]
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+#[cfg(feature = "std")]
+impl Backoff for Park {
+    fn snooze(&mut self, lock: &LockWaiter<'_>) {
+        let waker = self
+            .waker
+            .get_or_insert_with(|| Waker::from(Arc::new(Unpark(thread::current()))));
+        let wakers = lock.unlock_wakers();
+        let key = wakers.register(None, waker);
+        // an unlock between the caller's check and the register wouldn't unpark us
+        if lock.is_locked() {
+            thread::park();
+        }
+        wakers.deregister(key);
+    }
+}
@@ -0,0 +1,136 @@
+//! The lock and forward protocol every node of a log follows. A node's len carries
+//! a lock bit, held by the one writer allowed to append to it, and a forward bit, set
+//! once the node has been replaced by a newer one that it keeps a reference on.
+//! ArcLog's allocations and MmapArcLog's mappings both implement [`Node`], so taking
+//! the lock, following forwards and freeing a chain only exist once.
+
+use core::ptr::NonNull;
+use tracing::{event, instrument, Level};
+
+use crate::arc_log::{add_forward_to_len, has_forward, is_locked, lock_len};
+use crate::backoff::{Backoff, LockWaiter};
+use crate::sync::{fence, AtomicUsize, Ordering::*};
+use crate::waker_list::WakerHeader;
+
+/// # Safety
+///
+/// Every function takes a pointer to a live node. The accessors have to go through
+/// raw places instead of a reference to the whole node, the lock holder may be
+/// writing the rest of it at the same time.
+pub(crate) unsafe trait Node: Sized {
+    unsafe fn len<'a>(p_this: NonNull<Self>) -> &'a AtomicUsize;
+
+    unsafe fn count<'a>(p_this: NonNull<Self>) -> &'a AtomicUsize;
+
+    // only valid once the forward bit is set in len
+    unsafe fn forward(p_this: NonNull<Self>) -> NonNull<Self>;
+
+    // only for the lock holder, right before it sets the forward bit
+    unsafe fn write_forward(p_this: NonNull<Self>, p_new: NonNull<Self>);
+
+    // writers that parked instead of spinning on the lock
+    unsafe fn unlock_wakers<'a>(p_this: NonNull<Self>) -> &'a WakerHeader<4>;
+
+    // wakes whoever else waits on len, only called after the SeqCst fence in unlock
+    unsafe fn wake_fenced(p_this: NonNull<Self>);
+
+    // frees p_this once its last reference is gone, returns the forward it held one on
+    unsafe fn release(p_this: NonNull<Self>) -> Option<NonNull<Self>>;
+}
+
+// the last node in the chain that starts at p_this
+pub(crate) fn tail<N: Node>(mut p_this: NonNull<N>) -> NonNull<N> {
+    loop {
+        if !has_forward(unsafe { N::len(p_this) }.load(Acquire)) {
+            return p_this;
+        }
+        // SAFETY: the forward bit is only set after the forward is written
+        p_this = unsafe { N::forward(p_this) };
+    }
+}
+
+// Follows the forwards to the end of the chain and takes the lock bit there.
+// Returns the locked node and its len, or, if there is no backoff to wait
+// with and the lock is taken, the furthest node we saw
+#[instrument(skip(p_this, backoff))]
+pub(crate) fn lock_tail<N: Node>(
+    mut p_this: NonNull<N>,
+    mut backoff: Option<&mut dyn Backoff>,
+) -> Result<(NonNull<N>, usize), NonNull<N>> {
+    loop {
+        let len = unsafe { N::len(p_this) };
+        let raw_len = len.load(Acquire);
+        event!(Level::TRACE, "raw_len is {}", raw_len);
+        if has_forward(raw_len) {
+            event!(Level::TRACE, "has forward");
+            // SAFETY: the forward bit is only set after the forward is written
+            p_this = unsafe { N::forward(p_this) };
+        } else if is_locked(raw_len) {
+            let backoff = match backoff.as_deref_mut() {
+                Some(backoff) => backoff,
+                None => return Err(p_this),
+            };
+            event!(Level::TRACE, "locked, waiting for unlock");
+            backoff.snooze(&LockWaiter::new(len, unsafe { N::unlock_wakers(p_this) }));
+        } else {
+            match len.compare_exchange(raw_len, lock_len(raw_len), Acquire, Acquire) {
+                Ok(_) => {
+                    event!(Level::TRACE, "got new lock");
+                    return Ok((p_this, raw_len));
+                }
+                Err(_) if backoff.is_none() => return Err(p_this),
+                Err(new_value) => {
+                    event!(Level::TRACE, "len changed, new value is {}", new_value);
+                }
+            }
+        }
+    }
+}
+
+#[inline]
+pub(crate) fn unlock<N: Node>(p_this: NonNull<N>, len: usize) {
+    unsafe {
+        N::len(p_this).store(len, Release);
+        // Parked writers and everyone else waiting on len share one fence, after it
+        // each list only costs a load unless someone is waiting.
+        fence(SeqCst);
+        N::unlock_wakers(p_this).wake_all_fenced();
+        N::wake_fenced(p_this);
+    }
+}
+
+// publishes p_new as the forward of the locked p_this, which unlocks p_this for good
+pub(crate) fn set_forward<N: Node>(p_this: NonNull<N>, len: usize, p_new: NonNull<N>) {
+    unsafe { N::write_forward(p_this, p_new) };
+    // the Release store in unlock publishes the forward and everything written to
+    // p_new, and the forward has to be seen by the next writer
+    unlock(p_this, add_forward_to_len(len));
+}
+
+// the caller has to make sure p_this stays alive, by a reference or otherwise
+pub(crate) fn add_ref<N: Node>(p_this: NonNull<N>) {
+    unsafe { N::count(p_this) }.fetch_add(1, Relaxed);
+}
+
+// Drops one reference on p_this. If it was the last one p_this is freed, and the
+// reference it held on its forward is dropped the same way.
+#[inline(never)]
+pub(crate) fn drop_ref<N: Node>(mut p_this: NonNull<N>) {
+    loop {
+        // this is release because we need to capture all loads before we deallocate,
+        // like Arc does
+        let count = unsafe { N::count(p_this) };
+        if count.fetch_sub(1, Release) != 1 {
+            event!(Level::TRACE, "more than one ref, no need to drop");
+            return;
+        }
+        // pairs with the release above, so no access through another reference
+        // can creep past the free
+        count.load(Acquire);
+        event!(Level::TRACE, "last ref so have to drop");
+        match unsafe { N::release(p_this) } {
+            Some(p_forward) => p_this = p_forward,
+            None => return,
+        }
+    }
+}
@@ -0,0 +1,257 @@
+use alloc::alloc::{Allocator, Global};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::fmt;
+use core::mem;
+use core::ops::{Deref, Index};
+use core::ptr::{self, NonNull};
+use core::slice::SliceIndex;
+use crate::sync::{AtomicPtr, AtomicUsize, Ordering::*};
+
+use crate::arc_log::{handle_reserve, ArcLog, ArcLogInner, Freeze, LogIndex};
+use crate::chain::{self, drop_ref};
+use crate::backoff::Spin;
+
+/// An ArcLog whose handles don't keep old allocations alive.
+///
+/// A plain [`ArcLog`] handle holds a reference on the allocation it last saw, so one
+/// idle clone pins it, and every allocation after it, until it calls `update`.
+/// Handles of an EpochArcLog hold nothing but the log itself. Reads go through a
+/// short lived [`EpochGuard`] that always starts at the newest allocation, and an
+/// allocation that was grown out of is freed as soon as no guard is active.
+///
+/// The trade-off is that a steady stream of overlapping guards can put off freeing
+/// stale allocations, they're only freed once the active count drops to zero.
+pub struct EpochArcLog<T, A: Allocator = Global> {
+    domain: Arc<EpochDomain<T, A>, A>,
+}
+
+struct EpochDomain<T, A: Allocator> {
+    // the newest allocation we know of, holds one reference on it
+    current: AtomicPtr<ArcLogInner<T, A>>,
+    // number of live guards
+    active: AtomicUsize,
+    // allocations that were current once, each still holding the domain's reference
+    retired: AtomicPtr<Retired<T, A>>,
+    // the log's allocator, the retired list is allocated from it as well
+    alloc: A,
+}
+
+struct Retired<T, A: Allocator> {
+    ptr: NonNull<ArcLogInner<T, A>>,
+    next: *mut Retired<T, A>,
+}
+
+/// A pinned view of an [`EpochArcLog`], see [`EpochArcLog::pin`]
+pub struct EpochGuard<'a, T, A: Allocator = Global> {
+    log: &'a EpochArcLog<T, A>,
+    ptr: NonNull<ArcLogInner<T, A>>,
+}
+
+impl<T: Sync + Freeze, A: Allocator + Clone> ArcLog<T, A> {
+    /// switches this handle to guard based reclamation, see [`EpochArcLog`]
+    pub fn into_epoch(mut self) -> EpochArcLog<T, A> {
+        self.update();
+        let alloc = self.allocator().clone();
+        let p_current = self.into_raw();
+        EpochArcLog {
+            domain: Arc::new_in(
+                EpochDomain {
+                    current: AtomicPtr::new(p_current.as_ptr()),
+                    active: AtomicUsize::new(0),
+                    retired: AtomicPtr::new(ptr::null_mut()),
+                    alloc: alloc.clone(),
+                },
+                alloc,
+            ),
+        }
+    }
+}
+
+impl<T, A: Allocator> EpochDomain<T, A> {
+    // must be pinned. Moves current to the end of the forward chain, retiring the old one
+    fn advance(&self) -> NonNull<ArcLogInner<T, A>> {
+        loop {
+            let p_current = self.current.load(SeqCst);
+            // SAFETY: current is never null and we are pinned
+            let p_current = unsafe { NonNull::new_unchecked(p_current) };
+            let p_tail = chain::tail(p_current);
+            if p_tail == p_current {
+                return p_current;
+            }
+            // p_current holds a reference on its forward chain, so the tail is alive
+            chain::add_ref(p_tail);
+            match self
+                .current
+                .compare_exchange(p_current.as_ptr(), p_tail.as_ptr(), SeqCst, SeqCst)
+            {
+                Ok(_) => {
+                    self.retire(p_current);
+                    return p_tail;
+                }
+                // someone else moved it, give our reference back and look again
+                Err(_) => drop_ref(p_tail),
+            }
+        }
+    }
+
+    fn retire(&self, ptr: NonNull<ArcLogInner<T, A>>) {
+        let node: *mut Retired<T, A> = Box::leak(Box::new_in(
+            Retired {
+                ptr,
+                next: ptr::null_mut(),
+            },
+            &self.alloc,
+        ));
+        self.push_retired(node, node);
+    }
+
+    // pushes the list first..=last onto the retired stack
+    fn push_retired(&self, first: *mut Retired<T, A>, last: *mut Retired<T, A>) {
+        let mut head = self.retired.load(Relaxed);
+        loop {
+            unsafe { (*last).next = head };
+            // SeqCst, reclaim checks active after handing a list back and the last
+            // guard checks retired after dropping active, one of them has to see the other
+            match self
+                .retired
+                .compare_exchange_weak(head, first, SeqCst, Relaxed)
+            {
+                Ok(_) => return,
+                Err(h) => head = h,
+            }
+        }
+    }
+
+    // Frees the retired allocations if no guard is active. A guard that could have
+    // seen one of them pinned before it stopped being current, so once we own the
+    // list and see no guards, nobody can be looking at anything on it.
+    fn reclaim(&self) {
+        loop {
+            if self.active.load(SeqCst) != 0 || self.retired.load(SeqCst).is_null() {
+                return;
+            }
+            let mut node = self.retired.swap(ptr::null_mut(), SeqCst);
+            if node.is_null() {
+                return;
+            }
+            if self.active.load(SeqCst) == 0 {
+                while !node.is_null() {
+                    let retired = unsafe { Box::from_raw_in(node, &self.alloc) };
+                    node = retired.next;
+                    drop_ref(retired.ptr);
+                }
+                return;
+            }
+            // A new guard may still be on one of them, so the list goes back. That guard
+            // may also be gone already, having found nothing to free, so look again
+            // instead of leaving it to the last guard.
+            let mut last = node;
+            while unsafe { !(*last).next.is_null() } {
+                last = unsafe { (*last).next };
+            }
+            self.push_retired(node, last);
+        }
+    }
+}
+
+impl<T, A: Allocator> Drop for EpochDomain<T, A> {
+    fn drop(&mut self) {
+        // no handle is left, so there can't be a guard either
+        let mut node = self.retired.load(Relaxed);
+        while !node.is_null() {
+            let retired = unsafe { Box::from_raw_in(node, &self.alloc) };
+            node = retired.next;
+            drop_ref(retired.ptr);
+        }
+        drop_ref(unsafe { NonNull::new_unchecked(self.current.load(Relaxed)) });
+    }
+}
+
+impl<T: Sync + Freeze, A: Allocator + Clone> EpochArcLog<T, A> {
+    /// appends the item, returns its index
+    pub fn push_spin(&self, item: T) -> usize {
+        let guard = self.pin();
+        let (index, o_ptr) = ArcLogInner::alloc_items(guard.ptr, &item, 1, usize::MAX, &mut Spin);
+        let index = match handle_reserve(index) {
+            -1 => unreachable!(),
+            index => index as usize,
+        };
+        // the log owns a copy of it now
+        mem::forget(item);
+        if o_ptr.is_some() {
+            self.domain.advance();
+        }
+        index
+    }
+}
+
+impl<T, A: Allocator> EpochArcLog<T, A> {
+    /// Pins the newest allocation for as long as the guard lives. Keep guards short,
+    /// nothing retired can be freed while any guard of the log is active
+    pub fn pin(&self) -> EpochGuard<'_, T, A> {
+        // the increment has to come before we read current, see reclaim
+        self.domain.active.fetch_add(1, SeqCst);
+        let ptr = self.domain.advance();
+        EpochGuard { log: self, ptr }
+    }
+
+    /// number of guards currently active on the log
+    pub fn active_guards(&self) -> usize {
+        self.domain.active.load(Relaxed)
+    }
+}
+
+impl<T, A: Allocator + Clone> Clone for EpochArcLog<T, A> {
+    fn clone(&self) -> Self {
+        EpochArcLog {
+            domain: self.domain.clone(),
+        }
+    }
+}
+
+unsafe impl<T: Send + Sync, A: Allocator + Send + Sync> Send for EpochArcLog<T, A> {}
+unsafe impl<T: Send + Sync, A: Allocator + Send + Sync> Sync for EpochArcLog<T, A> {}
+
+impl<T: fmt::Debug, A: Allocator> fmt::Debug for EpochArcLog<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let guard = self.pin();
+        f.debug_struct("EpochArcLog")
+            .field("start_index", &guard.start_index())
+            .field("data", &&*guard)
+            .finish()
+    }
+}
+
+impl<T, A: Allocator> EpochGuard<'_, T, A> {
+    /// global index of the first item still retained
+    pub fn start_index(&self) -> usize {
+        ArcLogInner::start_index(self.ptr)
+    }
+}
+
+impl<T, A: Allocator> Drop for EpochGuard<'_, T, A> {
+    fn drop(&mut self) {
+        let domain = &self.log.domain;
+        if domain.active.fetch_sub(1, SeqCst) == 1 {
+            domain.reclaim();
+        }
+    }
+}
+
+impl<T, A: Allocator> Deref for EpochGuard<'_, T, A> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        // SAFETY: the allocation can't be freed while we're active
+        unsafe { ArcLogInner::items(self.ptr) }
+    }
+}
+
+impl<T, A: Allocator, I: LogIndex + SliceIndex<[T]>> Index<I> for EpochGuard<'_, T, A> {
+    type Output = I::Output;
+    #[inline]
+    fn index(&self, index: I) -> &Self::Output {
+        Index::index(&**self, index.rebase(self.start_index()))
+    }
+}
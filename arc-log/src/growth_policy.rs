@@ -0,0 +1,76 @@
+use core::cmp;
+
+use crate::arc_log::{has_forward_or_lock, min_non_zero_cap};
+
+/// Picks the capacity of the next allocation when an [`ArcLog`](crate::ArcLog) has to grow.
+///
+/// Every reallocation copies the items that are kept, so a policy trades memory for
+/// how often writers pay for that copy. The policy is chosen when the log is created
+/// and shared by every handle. Whatever it returns is raised to at least `required`
+/// and the smallest allocation for the item size.
+pub trait GrowthPolicy: Send + Sync {
+    /// `cap` is the capacity being grown from (the items kept if the log was just
+    /// compacted), `required` is the smallest capacity that fits the pending write
+    fn next_capacity(&self, cap: usize, required: usize) -> usize;
+}
+
+// What a log actually grows to: the policy's pick raised to `required` and the
+// smallest allocation for the item size. The policy can ask for more than the len
+// encoding allows, then it only gets `required`.
+pub(crate) fn grown_capacity(
+    policy: &dyn GrowthPolicy,
+    cap: usize,
+    required: usize,
+    elem_size: usize,
+) -> usize {
+    let n_cap = cmp::max(policy.next_capacity(cap, required), required);
+    let n_cap = cmp::max(min_non_zero_cap(elem_size), n_cap);
+    if has_forward_or_lock(n_cap) {
+        required
+    } else {
+        n_cap
+    }
+}
+
+/// Doubles the capacity, the same growth as Vec. This is the default
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Doubling;
+
+impl GrowthPolicy for Doubling {
+    fn next_capacity(&self, cap: usize, _required: usize) -> usize {
+        cap.saturating_mul(2)
+    }
+}
+
+/// Grows by half the current capacity, less memory left unused but more copies
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OneAndAHalf;
+
+impl GrowthPolicy for OneAndAHalf {
+    fn next_capacity(&self, cap: usize, _required: usize) -> usize {
+        cap.saturating_add(cap / 2)
+    }
+}
+
+/// Grows by the same number of items every time
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FixedChunk(pub usize);
+
+impl GrowthPolicy for FixedChunk {
+    fn next_capacity(&self, cap: usize, _required: usize) -> usize {
+        cap.saturating_add(self.0)
+    }
+}
+
+/// Doubles until a single step would add more than `max_step` items,
+/// then grows by `max_step`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capped {
+    pub max_step: usize,
+}
+
+impl GrowthPolicy for Capped {
+    fn next_capacity(&self, cap: usize, _required: usize) -> usize {
+        cap.saturating_add(cmp::min(cap, self.max_step))
+    }
+}
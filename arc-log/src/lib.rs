@@ -3,7 +3,6 @@
     slice_ptr_get,
     auto_traits,
     negative_impls,
-    ptr_metadata,
     new_uninit,
     maybe_uninit_slice,
     maybe_uninit_uninit_array
@@ -11,9 +10,25 @@
 #![no_std]
 
 extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+mod sync;
+mod chain;
 pub mod arc_log;
-//pub mod log_fragment;
+pub mod log_fragment;
 pub use crate::arc_log::*;
-//pub mod waker_list;
-//pub use waker_list::*;
+pub use crate::log_fragment::*;
+pub mod waker_list;
+pub use waker_list::*;
+pub mod growth_policy;
+pub use growth_policy::*;
+pub mod backoff;
+pub mod reader;
+pub use reader::*;
+pub mod epoch;
+pub use epoch::*;
+#[cfg(feature = "mmap")]
+pub mod mmap;
+#[cfg(feature = "mmap")]
+pub use mmap::MmapArcLog;
 
@@ -1,126 +1,352 @@
-use core::marker::PhantomData;
-use core::sync::atomic::{AtomicUsize, AtomicIsize, AtomicBool, AtomicPtr};
-use core::ptr::NonNull;
-use alloc::alloc::AllocRef;
-use alloc::vec::Vec;
-
-
-pub struct LogFragment<T, A: AllocRef> {
-    ptr: NonNull<InnerLogFragmentHeader<A>>,
-    index: usize,
-    pd: PhantomData<InnerLogFragment<T, A>> 
-}
-
-struct InnerLogFragmentHeader<A: AllocRef> {
-    forward: AtomicPtr<InnerLogFragmentHeader<A>>,
-    count: AtomicUsize,
-    len: AtomicIsize,
-    cap: AtomicUsize,
-    vec_lock: AtomicBool,
-    min_vec: Vec::<usize>,
-    start_index: usize,
-    alloc: A,
-}
-
-#[repr(C)]
-struct InnerLogFragment<T, A: AllocRef> {
-    header: InnerLogFragmentHeader<A>,
-    data: [T]
-}
-
-impl<T, A: AllocRef> LogFragment<T, A> {
-    pub fn new_in(alloc: A) -> Self {
-        LogFragment {
-            ptr: InnerLogFragmentHeader::with_capacity(0, alloc),
-            index: 0,
-            pd: PhantomData,
-        }
-    }
-
-    pub fn with_capacity_in(capacity: usize, alloc: A) -> Self {
-        ArcLog {
-            ptr: ArcLogInner::with_capacity(capacity, alloc),
-            pd: PhantomData,
-        }
-    }
-
-    #[instrument(skip(self))]
-    pub fn update(&mut self) -> bool {
-        let mut new_this = self.get_inner().header.forward.load(Acquire);
-        if new_this.is_null() {
-            false
-        } else {
-            // SAFETY: We just checked for null, and forward must be valid if it exists
-            let mut this = unsafe { &*new_this };
-            loop {
-                // this has to be acquire, because we may access data after this forward
-                new_this = this.header.forward.load(Acquire);
-                if new_this.is_null() {
-                    event!(Level::TRACE, "at end of forward change");
-                    break;
-                } else {
-                    event!(Level::TRACE, "had to forward");
-                    // SAFETY: We just checked for null, and forward must be valid if it exists
-                    this = unsafe { &*new_this };
-                }
-            }
-            let old_ptr = self.ptr;
-            self.ptr = this.into();
-            // this can be relaxed because only the count matters
-            // and we already did an acquire when we got the pointer
-            this.header.count.fetch_add(1, Relaxed);
-            drop_ref(old_ptr);
-            true
-        }
-    }
-
-    fn finish_push(
-        &mut self,
-        index: isize,
-        o_ptr: Option<NonNull<ArcLogInner<T, A>>>,
-        item: T,
-    ) -> Result<usize, T> {
-        if let Some(new_ptr) = o_ptr {
-            event!(Level::TRACE, "post alloc_one");
-            let old_ptr = self.ptr;
-            unsafe { new_ptr.as_ref().header.count.fetch_add(1, Relaxed) };
-            self.ptr = new_ptr;
-            event!(Level::TRACE, "pre-drop ref");
-            drop_ref(old_ptr);
-            event!(Level::TRACE, "post drop ref")
-        }
-        event!(Level::TRACE, "finished push");
-        if index == -1 {
-            Err(item)
-        } else {
-            let _ = ManuallyDrop::new(item);
-            Ok(index as usize)
-        }
-    }
-
-    #[instrument(skip(self, item))]
-    pub fn push_spin(&mut self, item: T) -> usize {
-        let (index, o_ptr) = self.get_inner().alloc_items(&item, 1, isize::MAX);
-        match self.finish_push(index, o_ptr, item) {
-            Ok(i) => i,
-            Err(_) => unreachable!(),
-        }
-    }
-
-    #[instrument(skip(self, item))]
-    pub fn push_or_return(&mut self, item: T) -> Result<usize, T> {
-        let (index, o_ptr) = self.get_inner().alloc_items_one_shot(&item, 1, isize::MAX);
-        self.finish_push(index, o_ptr, item)
-    }
-    #[instrument(skip(self, item))]
-    pub fn push_spin_by_index(&mut self, item: T, index: usize) -> Result<usize, T> {
-        let (index, o_ptr) = self.get_inner().alloc_items(&item, 1, index as isize);
-        self.finish_push(index, o_ptr, item)
-    }
-    pub fn push_or_return_by_index(&mut self, item: T, index: usize) -> Result<usize, T> {
-        let (index, o_ptr) = self
-            .get_inner()
-            .alloc_items_one_shot(&item, 1, index as isize);
-        self.finish_push(index, o_ptr, item)
-    }
-}
+use alloc::alloc::{handle_alloc_error, Allocator, Global, Layout};
+use alloc::vec::Vec;
+use core::cmp;
+use core::fmt;
+use crate::sync::hint;
+use core::marker::PhantomData;
+use core::mem::{self, ManuallyDrop};
+use core::ops::Index;
+use core::ptr::{self, NonNull};
+use core::slice;
+use crate::sync::{AtomicPtr, AtomicUsize, Ordering::*};
+use tracing::{event, instrument, Level};
+
+use crate::arc_log::{get_len, has_forward_or_lock, is_locked, lock_len, min_non_zero_cap};
+
+// enough fragments to address every index len can encode
+const MAX_FRAGMENTS: usize = usize::BITS as usize;
+
+/// An append only log built from fixed fragments that are never moved.
+///
+/// Each fragment is twice the size of the one before it, so growing only allocates
+/// the next fragment and never copies. That also means items don't need to be
+/// [`Freeze`](crate::Freeze), and there is no forward chain to follow: every clone sees
+/// new items as soon as they are pushed. The trade-off is that the log can't
+/// dereference to one slice; use [`get`](Self::get), indexing, or [`chunks`](Self::chunks).
+pub struct LogFragment<T, A: Allocator = Global> {
+    ptr: NonNull<InnerLogFragmentHeader<T, A>>,
+    pd: PhantomData<InnerLogFragmentHeader<T, A>>,
+}
+
+struct InnerLogFragmentHeader<T, A: Allocator> {
+    count: AtomicUsize,
+    // same encoding as ArcLog, but the forward bit is never set
+    len: AtomicUsize,
+    // fragment k holds first << k items and starts at index first * (2^k - 1),
+    // first is always a power of two
+    first_shift: u32,
+    // a fragment is published here before any len that reaches into it
+    fragments: [AtomicPtr<T>; MAX_FRAGMENTS],
+    alloc: A,
+}
+
+impl<T: Sync> LogFragment<T> {
+    pub fn new() -> Self {
+        LogFragment::new_in(Global)
+    }
+    pub fn with_capacity(capacity: usize) -> Self {
+        LogFragment::with_capacity_in(capacity, Global)
+    }
+}
+
+impl<T: Sync> Default for LogFragment<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl<T: Send + Sync, A: Allocator + Send + Sync> Send for LogFragment<T, A> {}
+impl<T, A: Allocator> Unpin for LogFragment<T, A> {}
+
+impl<T, A: Allocator> Clone for LogFragment<T, A> {
+    fn clone(&self) -> Self {
+        self.header().count.fetch_add(1, Relaxed);
+        LogFragment {
+            ptr: self.ptr,
+            pd: PhantomData,
+        }
+    }
+}
+
+impl<T, A: Allocator> Drop for LogFragment<T, A> {
+    fn drop(&mut self) {
+        // same as ArcLog, release so every use happens before the free
+        if self.header().count.fetch_sub(1, Release) != 1 {
+            return;
+        }
+        self.header().count.load(Acquire);
+        event!(Level::TRACE, "last ref so have to drop");
+        let len = self.len();
+        unsafe {
+            let header = self.ptr.as_ptr();
+            let alloc = ptr::read(&(*header).alloc);
+            let mut start = 0;
+            for (k, fragment) in (*header).fragments.iter().enumerate() {
+                let p_fragment = fragment.load(Relaxed);
+                if p_fragment.is_null() {
+                    break;
+                }
+                let cap = self.fragment_cap(k);
+                if start < len {
+                    ptr::drop_in_place(ptr::slice_from_raw_parts_mut(
+                        p_fragment,
+                        cmp::min(len - start, cap),
+                    ));
+                }
+                start += cap;
+                alloc.deallocate(
+                    NonNull::new_unchecked(p_fragment as *mut u8),
+                    Layout::array::<T>(cap).unwrap(),
+                );
+            }
+            alloc.deallocate(
+                NonNull::new_unchecked(header as *mut u8),
+                Layout::new::<InnerLogFragmentHeader<T, A>>(),
+            );
+        }
+    }
+}
+
+impl<T: fmt::Debug, A: Allocator> fmt::Debug for LogFragment<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let len_raw = self.header().len.load(Acquire);
+        f.debug_struct("LogFragment")
+            .field("ptr", &self.ptr)
+            .field("count", &self.header().count.load(Relaxed))
+            .field("is_locked", &is_locked(len_raw))
+            .field("len", &get_len(len_raw))
+            .field("data", &self.chunks_to(get_len(len_raw)).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl<T, A: Allocator> Index<usize> for LogFragment<T, A> {
+    type Output = T;
+    #[inline]
+    fn index(&self, index: usize) -> &T {
+        match self.get(index) {
+            Some(item) => item,
+            None => panic!("index {} out of bounds for len {}", index, self.len()),
+        }
+    }
+}
+
+impl<T, A: Allocator> LogFragment<T, A> {
+    #[inline]
+    fn header(&self) -> &InnerLogFragmentHeader<T, A> {
+        unsafe { self.ptr.as_ref() }
+    }
+
+    #[inline]
+    fn fragment_cap(&self, k: usize) -> usize {
+        1 << (k as u32 + self.header().first_shift)
+    }
+
+    // fragment and offset in the fragment of an index
+    #[inline]
+    fn locate(&self, index: usize) -> (usize, usize) {
+        let shift = self.header().first_shift;
+        let j = index + (1 << shift);
+        let top = usize::BITS - 1 - j.leading_zeros();
+        let k = (top - shift) as usize;
+        (k, j - (1 << top))
+    }
+
+    /// len at the time of the call, items below it never move
+    pub fn len(&self) -> usize {
+        get_len(self.header().len.load(Acquire))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, index: usize) -> Option<&T> {
+        if index >= self.len() {
+            return None;
+        }
+        let (k, offset) = self.locate(index);
+        // the Acquire on len makes the fragment pointer visible
+        let p_fragment = self.header().fragments[k].load(Relaxed);
+        Some(unsafe { &*p_fragment.add(offset) })
+    }
+
+    /// the items as one slice per fragment, up to the len when this was called
+    pub fn chunks(&self) -> FragmentChunks<'_, T, A> {
+        self.chunks_to(self.len())
+    }
+
+    fn chunks_to(&self, end: usize) -> FragmentChunks<'_, T, A> {
+        FragmentChunks {
+            log: self,
+            next: 0,
+            end,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.chunks().flat_map(|chunk| chunk.iter())
+    }
+}
+
+/// Iterator over the fragments of a [`LogFragment`], see [`LogFragment::chunks`]
+pub struct FragmentChunks<'a, T, A: Allocator = Global> {
+    log: &'a LogFragment<T, A>,
+    next: usize,
+    end: usize,
+}
+
+impl<'a, T, A: Allocator> Iterator for FragmentChunks<'a, T, A> {
+    type Item = &'a [T];
+
+    fn next(&mut self) -> Option<&'a [T]> {
+        if self.next >= self.end {
+            return None;
+        }
+        let (k, offset) = self.log.locate(self.next);
+        let n = cmp::min(self.end - self.next, self.log.fragment_cap(k) - offset);
+        let p_fragment = self.log.header().fragments[k].load(Relaxed);
+        self.next += n;
+        Some(unsafe { slice::from_raw_parts(p_fragment.add(offset), n) })
+    }
+}
+
+impl<T: Sync, A: Allocator> LogFragment<T, A> {
+    pub fn new_in(alloc: A) -> Self {
+        Self::with_capacity_in(0, alloc)
+    }
+
+    /// the first fragment holds at least `capacity` items
+    pub fn with_capacity_in(capacity: usize, alloc: A) -> Self {
+        let first = cmp::max(min_non_zero_cap(mem::size_of::<T>()), capacity).next_power_of_two();
+        let layout = Layout::new::<InnerLogFragmentHeader<T, A>>();
+        let p_header = alloc
+            .allocate(layout)
+            .unwrap_or_else(|_| handle_alloc_error(layout))
+            .as_ptr() as *mut InnerLogFragmentHeader<T, A>;
+        unsafe {
+            p_header.write(InnerLogFragmentHeader {
+                count: AtomicUsize::new(1),
+                len: AtomicUsize::new(0),
+                first_shift: first.trailing_zeros(),
+                fragments: core::array::from_fn(|_| AtomicPtr::new(ptr::null_mut())),
+                alloc,
+            });
+        }
+        LogFragment {
+            ptr: unsafe { NonNull::new_unchecked(p_header) },
+            pd: PhantomData,
+        }
+    }
+
+    // takes the lock bit, None if it was held (or changed) and we can't wait
+    fn lock(&self, spin: bool) -> Option<usize> {
+        let header = self.header();
+        loop {
+            let raw_len = header.len.load(Relaxed);
+            if !is_locked(raw_len)
+                && header
+                    .len
+                    .compare_exchange(raw_len, lock_len(raw_len), Acquire, Relaxed)
+                    .is_ok()
+            {
+                return Some(raw_len);
+            }
+            if !spin {
+                return None;
+            }
+            event!(Level::TRACE, "locked, waiting for unlock");
+            hint::spin_loop();
+        }
+    }
+
+    // must hold the lock. Allocates any fragment up to new_len that doesn't exist
+    // yet and copies the items in, returns the index of the first one
+    fn write_locked(&self, len: usize, data_ptr: *const T, count: usize) -> isize {
+        let header = self.header();
+        let new_len = match len.checked_add(count) {
+            Some(new_len) if !has_forward_or_lock(new_len) => new_len,
+            _ => {
+                header.len.store(len, Release);
+                panic!("capacity overflow");
+            }
+        };
+        let mut index = len;
+        let mut src = data_ptr;
+        while index < new_len {
+            let (k, offset) = self.locate(index);
+            let cap = self.fragment_cap(k);
+            let mut p_fragment = header.fragments[k].load(Relaxed);
+            if p_fragment.is_null() {
+                event!(Level::TRACE, "allocating fragment {} with cap {}", k, cap);
+                let layout = Layout::array::<T>(cap).expect("capacity overflow");
+                p_fragment = match header.alloc.allocate(layout) {
+                    Ok(p) => p.as_ptr() as *mut T,
+                    Err(_) => {
+                        header.len.store(len, Release);
+                        handle_alloc_error(layout)
+                    }
+                };
+                header.fragments[k].store(p_fragment, Relaxed);
+            }
+            let n = cmp::min(new_len - index, cap - offset);
+            unsafe {
+                ptr::copy_nonoverlapping(src, p_fragment.add(offset), n);
+                src = src.add(n);
+            }
+            index += n;
+        }
+        // publishes the items and any new fragment pointers, and unlocks
+        header.len.store(new_len, Release);
+        len as isize
+    }
+
+    fn alloc_items(&self, data_ptr: *const T, count: usize, ref_index: usize, spin: bool) -> isize {
+        debug_assert!(count > 0);
+        let len = match self.lock(spin) {
+            Some(len) => len,
+            None => return -1,
+        };
+        if len > ref_index {
+            self.header().len.store(len, Release);
+            return -1;
+        }
+        self.write_locked(len, data_ptr, count)
+    }
+
+    fn finish_push(index: isize, item: T) -> Result<usize, T> {
+        if index == -1 {
+            Err(item)
+        } else {
+            let _ = ManuallyDrop::new(item);
+            Ok(index as usize)
+        }
+    }
+
+    /// returns the index of the item that was pushed
+    #[instrument(skip(self, item))]
+    pub fn push_spin(&mut self, item: T) -> usize {
+        let index = self.alloc_items(&item, 1, usize::MAX, true);
+        match Self::finish_push(index, item) {
+            Ok(i) => i,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    #[instrument(skip(self, item))]
+    pub fn push_or_return(&mut self, item: T) -> Result<usize, T> {
+        let index = self.alloc_items(&item, 1, usize::MAX, false);
+        Self::finish_push(index, item)
+    }
+
+    #[instrument(skip(self, item))]
+    pub fn push_spin_by_index(&mut self, item: T, index: usize) -> Result<usize, T> {
+        let index = self.alloc_items(&item, 1, index, true);
+        Self::finish_push(index, item)
+    }
+
+    pub fn push_or_return_by_index(&mut self, item: T, index: usize) -> Result<usize, T> {
+        let index = self.alloc_items(&item, 1, index, false);
+        Self::finish_push(index, item)
+    }
+}
@@ -0,0 +1,379 @@
+use alloc::sync::Arc;
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::Deref;
+use core::ptr::{self, NonNull};
+use core::slice;
+// the header lives in the file, so it's always a real atomic
+use core::sync::atomic::AtomicU64;
+use crate::sync::{AtomicPtr, AtomicUsize, Ordering::*};
+use std::boxed::Box;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+use bytemuck::Pod;
+use memmap2::MmapMut;
+use tracing::{event, Level};
+
+use crate::arc_log::{get_len, has_forward_or_lock};
+use crate::backoff::{Backoff, Spin};
+use crate::chain::{self, drop_ref, Node};
+use crate::growth_policy::{grown_capacity, Doubling, GrowthPolicy};
+use crate::waker_list::WakerHeader;
+
+const MAGIC: [u8; 8] = *b"ARCLOG\0\x01";
+
+// what sits at the start of the file, the items follow it at data_offset
+#[repr(C)]
+struct FileHeader {
+    magic: [u8; 8],
+    item_size: u64,
+    // only ever written by the lock holder, after the items it covers
+    len: AtomicU64,
+    cap: u64,
+}
+
+const fn data_offset<T>() -> usize {
+    let align = mem::align_of::<T>();
+    mem::size_of::<FileHeader>().div_ceil(align) * align
+}
+
+/// An append only log of plain old data that lives in a memory mapped file.
+///
+/// It works like an [`ArcLog`](crate::ArcLog): clones share the mapping, pushes
+/// take the same lock bit and wait on it with the same [`Backoff`] strategies, and
+/// growing forwards to a new node that other handles pick up on
+/// [`update`](Self::update). Growing extends the file by what the log's
+/// [`GrowthPolicy`] asks for and maps it again instead of copying, so the old
+/// mappings still see the same items.
+///
+/// The file starts with a header holding the item size, len and cap, which is
+/// updated on every push. [`open`](Self::open) rebuilds the log from it, call
+/// [`flush`](Self::flush) to make sure it reached the disk.
+///
+/// Only one process may have the file open for writing, and nothing else may
+/// modify it while it's mapped.
+pub struct MmapArcLog<T: Pod> {
+    ptr: NonNull<MmapInner<T>>,
+    pd: PhantomData<MmapInner<T>>,
+}
+
+// what every mapping of one file shares
+struct MmapShared {
+    file: File,
+    // writers that parked instead of spinning on the lock
+    unlock_wakers: WakerHeader<4>,
+    policy: Box<dyn GrowthPolicy>,
+}
+
+struct MmapInner<T> {
+    count: AtomicUsize,
+    // same encoding as ArcLog, lock and forward bits on top
+    len: AtomicUsize,
+    cap: usize,
+    // only set once, before the forward bit is published in len
+    forward: AtomicPtr<MmapInner<T>>,
+    // start of the mapping, taken from map while we still had it mutably
+    base: *mut u8,
+    map: MmapMut,
+    shared: Arc<MmapShared>,
+    pd: PhantomData<T>,
+}
+
+impl<T> MmapInner<T> {
+    fn header(&self) -> &FileHeader {
+        unsafe { &*(self.base as *const FileHeader) }
+    }
+
+    fn data(&self) -> *mut T {
+        unsafe { self.base.add(data_offset::<T>()) as *mut T }
+    }
+
+    fn map(shared: Arc<MmapShared>, count: usize, len: usize, cap: usize) -> io::Result<NonNull<Self>> {
+        // SAFETY: the type documents that nothing else may touch the file while it's mapped
+        let mut map = unsafe { MmapMut::map_mut(&shared.file)? };
+        let base = map.as_mut_ptr();
+        let inner = Box::new(MmapInner {
+            count: AtomicUsize::new(count),
+            len: AtomicUsize::new(len),
+            cap,
+            forward: AtomicPtr::new(ptr::null_mut()),
+            base,
+            map,
+            shared,
+            pd: PhantomData,
+        });
+        Ok(Box::leak(inner).into())
+    }
+}
+
+unsafe impl<T> Node for MmapInner<T> {
+    unsafe fn len<'a>(p_this: NonNull<Self>) -> &'a AtomicUsize {
+        &(*p_this.as_ptr()).len
+    }
+
+    unsafe fn count<'a>(p_this: NonNull<Self>) -> &'a AtomicUsize {
+        &(*p_this.as_ptr()).count
+    }
+
+    unsafe fn forward(p_this: NonNull<Self>) -> NonNull<Self> {
+        NonNull::new_unchecked((*p_this.as_ptr()).forward.load(Relaxed))
+    }
+
+    unsafe fn write_forward(p_this: NonNull<Self>, p_new: NonNull<Self>) {
+        (*p_this.as_ptr()).forward.store(p_new.as_ptr(), Relaxed);
+    }
+
+    unsafe fn unlock_wakers<'a>(p_this: NonNull<Self>) -> &'a WakerHeader<4> {
+        let shared: &'a Arc<MmapShared> = &(*p_this.as_ptr()).shared;
+        &shared.unlock_wakers
+    }
+
+    // only writers ever wait on a mapping
+    unsafe fn wake_fenced(_p_this: NonNull<Self>) {}
+
+    unsafe fn release(p_this: NonNull<Self>) -> Option<NonNull<Self>> {
+        // SAFETY: it was made with Box::leak
+        let inner = Box::from_raw(p_this.as_ptr());
+        NonNull::new(inner.forward.load(Relaxed))
+    }
+}
+
+impl MmapShared {
+    fn new<G: GrowthPolicy + 'static>(file: File, policy: G) -> Arc<Self> {
+        Arc::new(MmapShared {
+            file,
+            unlock_wakers: WakerHeader::new(),
+            policy: Box::new(policy),
+        })
+    }
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn file_len<T>(cap: usize) -> io::Result<u64> {
+    cap.checked_mul(mem::size_of::<T>())
+        .and_then(|size| size.checked_add(data_offset::<T>()))
+        .map(|size| size as u64)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "capacity overflow"))
+}
+
+impl<T: Pod> MmapArcLog<T> {
+    /// creates (or truncates) the file at `path` as an empty log with room for `capacity` items
+    pub fn create<P: AsRef<Path>>(path: P, capacity: usize) -> io::Result<Self> {
+        Self::create_with_policy(path, capacity, Doubling)
+    }
+
+    /// like create, but the file grows according to `policy`
+    pub fn create_with_policy<P: AsRef<Path>, G: GrowthPolicy + 'static>(
+        path: P,
+        capacity: usize,
+        policy: G,
+    ) -> io::Result<Self> {
+        assert!(mem::size_of::<T>() != 0, "zero sized items can't be stored in a file");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(file_len::<T>(capacity)?)?;
+        let p_inner = MmapInner::<T>::map(MmapShared::new(file, policy), 1, 0, capacity)?;
+        unsafe {
+            ptr::write(
+                (*p_inner.as_ptr()).base as *mut FileHeader,
+                FileHeader {
+                    magic: MAGIC,
+                    item_size: mem::size_of::<T>() as u64,
+                    len: AtomicU64::new(0),
+                    cap: capacity as u64,
+                },
+            );
+        }
+        Ok(MmapArcLog {
+            ptr: p_inner,
+            pd: PhantomData,
+        })
+    }
+
+    /// reopens a log that was made with [`create`](Self::create), with the len
+    /// the header had when it was last written
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::open_with_policy(path, Doubling)
+    }
+
+    /// like open, but the file grows according to `policy` from now on
+    pub fn open_with_policy<P: AsRef<Path>, G: GrowthPolicy + 'static>(
+        path: P,
+        policy: G,
+    ) -> io::Result<Self> {
+        assert!(mem::size_of::<T>() != 0, "zero sized items can't be stored in a file");
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let size = file.metadata()?.len();
+        if size < data_offset::<T>() as u64 {
+            return Err(invalid("file is too short for an ArcLog header"));
+        }
+        let p_inner = MmapInner::<T>::map(MmapShared::new(file, policy), 1, 0, 0)?;
+        let log = MmapArcLog {
+            ptr: p_inner,
+            pd: PhantomData,
+        };
+        let header = log.inner().header();
+        if header.magic != MAGIC {
+            return Err(invalid("not an ArcLog file"));
+        }
+        if header.item_size != mem::size_of::<T>() as u64 {
+            return Err(invalid("the file holds items of a different size"));
+        }
+        let (len, cap) = (header.len.load(Acquire), header.cap);
+        if len > cap || file_len::<T>(cap as usize)? > size || has_forward_or_lock(cap as usize) {
+            return Err(invalid("ArcLog header doesn't match the file"));
+        }
+        // nobody else can see the node yet
+        unsafe {
+            (*p_inner.as_ptr()).cap = cap as usize;
+            (*p_inner.as_ptr()).len.store(len as usize, Relaxed);
+        }
+        Ok(log)
+    }
+
+    #[inline]
+    fn inner(&self) -> &MmapInner<T> {
+        unsafe { self.ptr.as_ref() }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.inner().cap
+    }
+
+    /// moves to the newest mapping, returns false if there wasn't one
+    pub fn update(&mut self) -> bool {
+        self.move_to(chain::tail(self.ptr))
+    }
+
+    fn move_to(&mut self, p_this: NonNull<MmapInner<T>>) -> bool {
+        if p_this == self.ptr {
+            return false;
+        }
+        chain::add_ref(p_this);
+        let old = mem::replace(&mut self.ptr, p_this);
+        drop_ref(old);
+        true
+    }
+
+    /// returns the index of the item that was pushed
+    pub fn push_spin(&mut self, item: T) -> io::Result<usize> {
+        self.push_with_backoff(item, Spin)
+    }
+
+    /// Same as push_spin, but `backoff` decides what to do while another writer holds
+    /// the lock, see [`crate::backoff`]
+    pub fn push_with_backoff<B: Backoff>(&mut self, item: T, mut backoff: B) -> io::Result<usize> {
+        self.push_slice_with(slice::from_ref(&item), &mut backoff)
+    }
+
+    /// appends the items as one contiguous run, returns the index of the first one.
+    /// If the file can't be grown nothing is written and the lock is released
+    pub fn push_slice_spin(&mut self, items: &[T]) -> io::Result<usize> {
+        self.push_slice_with(items, &mut Spin)
+    }
+
+    fn push_slice_with(&mut self, items: &[T], backoff: &mut dyn Backoff) -> io::Result<usize> {
+        let (p_this, len) = match chain::lock_tail(self.ptr, Some(backoff)) {
+            Ok(locked) => locked,
+            Err(_) => unreachable!(),
+        };
+        let inner = unsafe { p_this.as_ref() };
+        let new_len = match len.checked_add(items.len()) {
+            Some(new_len) if !has_forward_or_lock(new_len) => new_len,
+            _ => {
+                chain::unlock(p_this, len);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "capacity overflow"));
+            }
+        };
+        if new_len <= inner.cap {
+            unsafe { ptr::copy_nonoverlapping(items.as_ptr(), inner.data().add(len), items.len()) };
+            inner.header().len.store(new_len as u64, Release);
+            chain::unlock(p_this, new_len);
+            self.move_to(p_this);
+            return Ok(len);
+        }
+        event!(Level::TRACE, "had to remap");
+        let n_cap = grown_capacity(&*inner.shared.policy, inner.cap, new_len, mem::size_of::<T>());
+        let p_new = match Self::remap(inner, len, n_cap) {
+            Ok(p_new) => p_new,
+            Err(e) => {
+                chain::unlock(p_this, len);
+                return Err(e);
+            }
+        };
+        let new = unsafe { p_new.as_ref() };
+        unsafe { ptr::copy_nonoverlapping(items.as_ptr(), new.data().add(len), items.len()) };
+        new.header().len.store(new_len as u64, Release);
+        new.len.store(new_len, Relaxed);
+        chain::set_forward(p_this, len, p_new);
+        self.move_to(p_new);
+        Ok(len)
+    }
+
+    // must hold the lock on inner. Grows the file and maps all of it again, the
+    // new node starts with one reference, owned by inner
+    fn remap(inner: &MmapInner<T>, len: usize, n_cap: usize) -> io::Result<NonNull<MmapInner<T>>> {
+        inner.shared.file.set_len(file_len::<T>(n_cap)?)?;
+        let p_new = MmapInner::<T>::map(inner.shared.clone(), 1, len, n_cap)?;
+        unsafe { (*((*p_new.as_ptr()).base as *mut FileHeader)).cap = n_cap as u64 };
+        Ok(p_new)
+    }
+
+    /// writes the header and every item this handle can see to the disk
+    pub fn flush(&self) -> io::Result<()> {
+        let len = self.len();
+        self.inner()
+            .map
+            .flush_range(0, data_offset::<T>() + len * mem::size_of::<T>())
+    }
+}
+
+unsafe impl<T: Pod + Sync> Send for MmapArcLog<T> {}
+impl<T: Pod> Unpin for MmapArcLog<T> {}
+
+impl<T: Pod> Clone for MmapArcLog<T> {
+    fn clone(&self) -> Self {
+        chain::add_ref(self.ptr);
+        MmapArcLog {
+            ptr: self.ptr,
+            pd: PhantomData,
+        }
+    }
+}
+
+impl<T: Pod> Drop for MmapArcLog<T> {
+    fn drop(&mut self) {
+        drop_ref(self.ptr);
+    }
+}
+
+impl<T: Pod> Deref for MmapArcLog<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        let inner = self.inner();
+        let len = get_len(inner.len.load(Acquire));
+        // SAFETY: everything below len was written before len was published
+        unsafe { slice::from_raw_parts(inner.data(), len) }
+    }
+}
+
+impl<T: Pod + fmt::Debug> fmt::Debug for MmapArcLog<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MmapArcLog")
+            .field("ptr", &self.ptr)
+            .field("cap", &self.inner().cap)
+            .field("data", &&**self)
+            .finish()
+    }
+}
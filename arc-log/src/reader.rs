@@ -0,0 +1,95 @@
+use alloc::alloc::{Allocator, Global};
+use core::fmt;
+use core::ops::{Deref, Index};
+use core::slice::{self, SliceIndex};
+
+use crate::arc_log::{ArcLog, Freeze, LogIndex};
+
+/// A handle on an [`ArcLog`] that can read and follow it, but never push.
+///
+/// It shares the same allocations and refcount as the writers, so cloning one is
+/// as cheap as cloning the log. A reader only turns back into a writer through
+/// [`upgrade`](Self::upgrade).
+///
+/// ```compile_fail
+/// let log = arc_log::ArcLog::<u64>::new();
+/// let mut reader = log.reader();
+/// reader.push_spin(1);
+/// ```
+pub struct ArcLogReader<T, A: Allocator = Global> {
+    log: ArcLog<T, A>,
+}
+
+impl<T: Sync + Freeze, A: Allocator + Clone> ArcLog<T, A> {
+    /// a read-only handle on this log
+    pub fn reader(&self) -> ArcLogReader<T, A> {
+        ArcLogReader { log: self.clone() }
+    }
+
+    /// turns this handle into a read-only one without touching the refcount
+    pub fn into_reader(self) -> ArcLogReader<T, A> {
+        ArcLogReader { log: self }
+    }
+}
+
+impl<T: Sync + Freeze, A: Allocator + Clone> ArcLogReader<T, A> {
+    /// moves to the newest allocation, returns false if there wasn't one
+    pub fn update(&mut self) -> bool {
+        self.log.update()
+    }
+
+    /// global index of the first item still retained, see [`ArcLog::start_index`]
+    pub fn start_index(&self) -> usize {
+        self.log.start_index()
+    }
+
+    /// global index one past the last item this handle can see
+    pub fn end_index(&self) -> usize {
+        self.log.end_index()
+    }
+
+    /// turns the reader back into a handle that can push
+    pub fn upgrade(self) -> ArcLog<T, A> {
+        self.log
+    }
+}
+
+impl<T, A: Allocator> Clone for ArcLogReader<T, A> {
+    fn clone(&self) -> Self {
+        ArcLogReader {
+            log: self.log.clone(),
+        }
+    }
+}
+
+impl<T, A: Allocator> Deref for ArcLogReader<T, A> {
+    type Target = [T];
+
+    #[inline]
+    fn deref(&self) -> &[T] {
+        &self.log
+    }
+}
+
+impl<T, A: Allocator, I: LogIndex + SliceIndex<[T]>> Index<I> for ArcLogReader<T, A> {
+    type Output = I::Output;
+    #[inline]
+    fn index(&self, index: I) -> &Self::Output {
+        Index::index(&self.log, index)
+    }
+}
+
+impl<'a, T, A: Allocator> IntoIterator for &'a ArcLogReader<T, A> {
+    type Item = &'a T;
+    type IntoIter = slice::Iter<'a, T>;
+
+    fn into_iter(self) -> slice::Iter<'a, T> {
+        self.iter()
+    }
+}
+
+impl<T: fmt::Debug, A: Allocator> fmt::Debug for ArcLogReader<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ArcLogReader").field(&self.log).finish()
+    }
+}
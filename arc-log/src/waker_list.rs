@@ -1,40 +1,292 @@
-use core::sync::atomic::AtomicUsize;
-use core::task::Waker;
-use core::mem::MaybeUninit;
-use alloc::boxed::Box;
-use alloc::sync::Arc;
-
-pub struct InnerWakers {
-    next_inner: Option<Box<InnerWakers>>,
-    wakers: [MaybeUninit<Waker>]
-}
-
-pub struct WakerHeader<const N: usize> {
-    len: AtomicUsize,
-    next_inner: Option<Box<InnerWakers>>,
-    wakers: [MaybeUninit<Waker>; N]
-}
-
-struct WakerSupplier<const N: usize> {
-    waker_list: Arc<WakerHeader<N>>
-}
-
-struct WakerWaker<const N: usize> {
-    waker_list: Arc<WakerHeader<N>>
-}
-
-impl <const N: usize> WakerWaker<N> {
-    pub fn new() -> Self {
-        Self {
-            waker_list: Arc::new(WakerHeader {
-                len: AtomicUsize::new(0),
-                next_inner: None,
-                wakers: MaybeUninit::uninit_array::<N>()
-            })
-        }
-    }
-}
-
-
-
-
+use core::cell::UnsafeCell;
+use crate::sync::hint;
+use core::ptr::{self, NonNull};
+use crate::sync::{fence, AtomicPtr, AtomicUsize, Ordering::*};
+use core::task::Waker;
+use alloc::alloc::{Allocator, Global};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+// slot states, a slot is owned by one waiter from the time it's claimed
+// until it's deregistered
+const FREE: usize = 0;
+// claimed, but doesn't hold a waker (never polled, or already woken)
+const IDLE: usize = 1;
+// claimed and holding a waker
+const WAITING: usize = 2;
+// someone is moving the waker in or out, this is only ever held for a swap
+const BUSY: usize = 3;
+
+struct WakerSlot {
+    state: AtomicUsize,
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+// SAFETY: the waker is only touched by whoever moved the state to BUSY
+unsafe impl Send for WakerSlot {}
+unsafe impl Sync for WakerSlot {}
+
+impl WakerSlot {
+    fn new() -> Self {
+        WakerSlot {
+            state: AtomicUsize::new(FREE),
+            waker: UnsafeCell::new(None),
+        }
+    }
+
+    // spins until the slot moves from one of the owned states to BUSY,
+    // returns the state it was in
+    fn lock_owned(&self) -> usize {
+        loop {
+            let state = self.state.load(Relaxed);
+            if (state == IDLE || state == WAITING)
+                && self
+                    .state
+                    .compare_exchange_weak(state, BUSY, Acquire, Relaxed)
+                    .is_ok()
+            {
+                return state;
+            }
+            hint::spin_loop();
+        }
+    }
+}
+
+/// Overflow block of the list. Every block is twice the size of the one
+/// before it and, once linked, is never moved or freed until the list is dropped.
+/// The block and its slots both come from the list's allocator.
+pub struct InnerWakers {
+    next_inner: AtomicPtr<InnerWakers>,
+    wakers: NonNull<[WakerSlot]>,
+}
+
+/// A lock-free, growable list of wakers with `N` inline slots.
+///
+/// Each waiter claims a slot once and gets back a [`WakerKey`] that it reuses
+/// every time it's polled, and hands back with [`deregister`](Self::deregister)
+/// when it goes away. [`wake_all`](Self::wake_all) wakes everyone currently waiting.
+/// Slots past the first `N` are allocated from `A`.
+pub struct WakerHeader<const N: usize, A: Allocator = Global> {
+    // number of slots holding a waker, lets wake_all skip the scan when nobody waits
+    len: AtomicUsize,
+    next_inner: AtomicPtr<InnerWakers>,
+    wakers: [WakerSlot; N],
+    alloc: A,
+}
+
+/// Identifies a claimed slot in a [`WakerHeader`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WakerKey(usize);
+
+impl<const N: usize> Default for WakerHeader<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> WakerHeader<N> {
+    pub fn new() -> Self {
+        Self::new_in(Global)
+    }
+}
+
+impl<const N: usize, A: Allocator> WakerHeader<N, A> {
+    /// an empty list that grows with blocks from `alloc`
+    pub fn new_in(alloc: A) -> Self {
+        WakerHeader {
+            len: AtomicUsize::new(0),
+            next_inner: AtomicPtr::new(ptr::null_mut()),
+            wakers: core::array::from_fn(|_| WakerSlot::new()),
+            alloc,
+        }
+    }
+
+    /// number of waiters currently holding a waker
+    pub fn len(&self) -> usize {
+        self.len.load(Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // walks every slot in key order until f returns true
+    fn find_slot(&self, mut f: impl FnMut(usize, &WakerSlot) -> bool) -> Option<usize> {
+        let mut base = 0;
+        let mut slots: &[WakerSlot] = &self.wakers;
+        let mut next = &self.next_inner;
+        loop {
+            for (i, slot) in slots.iter().enumerate() {
+                if f(base + i, slot) {
+                    return Some(base + i);
+                }
+            }
+            base += slots.len();
+            let p_next = next.load(Acquire);
+            if p_next.is_null() {
+                return None;
+            }
+            // SAFETY: linked blocks live as long as the list
+            let block = unsafe { &*p_next };
+            slots = unsafe { block.wakers.as_ref() };
+            next = &block.next_inner;
+        }
+    }
+
+    fn slot(&self, key: WakerKey) -> &WakerSlot {
+        let mut found = None;
+        self.find_slot(|i, slot| {
+            if i == key.0 {
+                found = Some(slot as *const WakerSlot);
+                true
+            } else {
+                false
+            }
+        });
+        // SAFETY: slots are never moved once linked
+        unsafe { &*found.expect("waker key from a different list") }
+    }
+
+    // links one more block onto the end of the chain, if another thread got
+    // there first we just use theirs
+    fn grow(&self) {
+        let mut next = &self.next_inner;
+        let mut size = if N == 0 { 1 } else { N };
+        loop {
+            let p_next = next.load(Acquire);
+            if p_next.is_null() {
+                break;
+            }
+            let block = unsafe { &*p_next };
+            size = block.wakers.len();
+            next = &block.next_inner;
+        }
+        let mut slots = Vec::with_capacity_in(size * 2, &self.alloc);
+        slots.extend((0..size * 2).map(|_| WakerSlot::new()));
+        // both are freed through the same allocator in drop
+        let wakers = NonNull::from(Box::leak(slots.into_boxed_slice()));
+        let block: *mut InnerWakers = Box::leak(Box::new_in(
+            InnerWakers {
+                next_inner: AtomicPtr::new(ptr::null_mut()),
+                wakers,
+            },
+            &self.alloc,
+        ));
+        loop {
+            match next.compare_exchange(ptr::null_mut(), block, AcqRel, Acquire) {
+                Ok(_) => return,
+                Err(other) => {
+                    // someone else grew the list, keep ours for the end of theirs
+                    next = unsafe { &(*other).next_inner };
+                }
+            }
+        }
+    }
+
+    /// Stores the waker, claiming a new slot if `key` is `None`.
+    /// Returns the key that should be passed in the next time.
+    pub fn register(&self, key: Option<WakerKey>, waker: &Waker) -> WakerKey {
+        let (key, slot) = match key {
+            Some(key) => {
+                let slot = self.slot(key);
+                let prev = slot.lock_owned();
+                if prev == IDLE {
+                    self.len.fetch_add(1, Relaxed);
+                }
+                (key, slot)
+            }
+            None => loop {
+                let mut claimed = None;
+                let found = self.find_slot(|_, slot| {
+                    if slot
+                        .state
+                        .compare_exchange(FREE, BUSY, Acquire, Relaxed)
+                        .is_ok()
+                    {
+                        claimed = Some(slot as *const WakerSlot);
+                        true
+                    } else {
+                        false
+                    }
+                });
+                match found {
+                    Some(i) => {
+                        self.len.fetch_add(1, Relaxed);
+                        // SAFETY: slots are never moved once linked
+                        break (WakerKey(i), unsafe { &*claimed.unwrap() });
+                    }
+                    None => self.grow(),
+                }
+            },
+        };
+        // SAFETY: we hold the slot in BUSY
+        let cur = unsafe { &mut *slot.waker.get() };
+        match cur {
+            Some(w) if w.will_wake(waker) => {}
+            _ => *cur = Some(waker.clone()),
+        }
+        slot.state.store(WAITING, Release);
+        // pairs with the fence in wake_all, either the waiter's next check of
+        // the condition sees the change or wake_all sees the waker
+        fence(SeqCst);
+        key
+    }
+
+    /// Gives the slot back, dropping any waker still stored in it
+    pub fn deregister(&self, key: WakerKey) {
+        let slot = self.slot(key);
+        let prev = slot.lock_owned();
+        // SAFETY: we hold the slot in BUSY
+        let waker = unsafe { (*slot.waker.get()).take() };
+        if prev == WAITING {
+            self.len.fetch_sub(1, Relaxed);
+        }
+        slot.state.store(FREE, Release);
+        drop(waker);
+    }
+
+    /// Wakes every registered waiter. Slots stay claimed, so a woken waiter
+    /// registers again with the same key.
+    pub fn wake_all(&self) {
+        fence(SeqCst);
+        self.wake_all_fenced();
+    }
+
+    /// Same as [`wake_all`](Self::wake_all) for a caller that already issued a SeqCst
+    /// fence after its change, so one fence can cover several lists.
+    pub fn wake_all_fenced(&self) {
+        if self.len.load(Relaxed) == 0 {
+            return;
+        }
+        self.find_slot(|_, slot| {
+            if slot
+                .state
+                .compare_exchange(WAITING, BUSY, Acquire, Relaxed)
+                .is_ok()
+            {
+                // SAFETY: we hold the slot in BUSY
+                let waker = unsafe { (*slot.waker.get()).take() };
+                self.len.fetch_sub(1, Relaxed);
+                slot.state.store(IDLE, Release);
+                if let Some(w) = waker {
+                    w.wake();
+                }
+            }
+            false
+        });
+    }
+}
+
+impl<const N: usize, A: Allocator> Drop for WakerHeader<N, A> {
+    fn drop(&mut self) {
+        // we have exclusive access, a plain load is enough (and loom's atomics have no get_mut)
+        let mut p_next = self.next_inner.load(Relaxed);
+        while !p_next.is_null() {
+            // SAFETY: every block and its slots were allocated in grow from self.alloc,
+            // and we have exclusive access
+            let block = unsafe { Box::from_raw_in(p_next, &self.alloc) };
+            p_next = block.next_inner.load(Relaxed);
+            drop(unsafe { Box::from_raw_in(block.wakers.as_ptr(), &self.alloc) });
+        }
+    }
+}
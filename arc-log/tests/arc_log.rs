@@ -1,3 +1,6 @@
+#![feature(allocator_api)]
+mod common;
+
 #[cfg(test)]
 mod tests {
     //use arc_log::ArcLog;
@@ -25,7 +28,7 @@ mod tests {
         //     .with_max_level(TEST_LEVEL)
         //     .with_test_writer()
         //     .try_init();
-        let mut v = unsafe { ArcLog::new()};
+        let mut v = ArcLog::new();
 
         v.push_spin(DropTest(1));
         // for i in 0..1 {
@@ -70,7 +73,7 @@ mod tests {
     //     //     .with_max_level(TEST_LEVEL)
     //     //     .with_test_writer()
     //     //     .try_init();
-    //     let mut v = unsafe {ArcLog::new()};
+    //     let mut v = ArcLog::new();
 
     //     v.push_spin(Box::new(AtomicUsize::new(1)));
     //     //v.push(2);
@@ -83,7 +86,7 @@ mod tests {
         //     .with_max_level(TEST_LEVEL)
         //     .with_test_writer()
         //     .try_init();
-        let mut v = unsafe { ArcLog::new() };
+        let mut v = ArcLog::new();
         //event!(Level::TRACE, "v::new() : {:?}", v);
         let mut v2 = v.clone();
         //event!(Level::TRACE, "v2::new() : {:?}", v2);
@@ -105,7 +108,7 @@ mod tests {
         //     .with_max_level(TEST_LEVEL)
         //     .with_test_writer()
         //     .try_init();
-        let mut copy_1 = unsafe { ArcLog::new() };
+        let mut copy_1 = ArcLog::new();
         //event!(Level::TRACE, "Copy_1::new() : {:?}", copy_1);
         let mut copy_2 = copy_1.clone();
         //event!(Level::TRACE, "Copy_2::clone() : {:?} , {:?}", copy_1, copy_2);
@@ -132,7 +135,7 @@ mod tests {
         //     .with_max_level(TEST_LEVEL)
         //     .with_test_writer()
         //     .try_init();
-        let mut copy_1 = unsafe { ArcLog::new() };
+        let mut copy_1 = ArcLog::new();
         //event!(Level::TRACE, "Copy_1::new() : {:?}", copy_1);
         let mut copy_2 = copy_1.clone();
         //event!(Level::TRACE, "Copy_2::clone() : {:?}", copy_2);
@@ -148,13 +151,12 @@ mod tests {
     }
 
     #[test]
-    //#[cfg_attr(miri, ignore)]
     fn mt_test() {
         // let _ = tracing_subscriber::fmt()
         //     .with_max_level(TEST_LEVEL)
         //     .with_test_writer()
         //     .try_init();
-        let mut v = unsafe { ArcLog::new() };
+        let mut v = ArcLog::new();
         let v2 = v.clone();
         let handle1 = thread::spawn(move || {
             let mut v2 = v2;
@@ -199,4 +201,574 @@ mod tests {
         assert_eq!(v.iter().filter(|t| **t == 3).count(), 100);
         assert_eq!(v.iter().filter(|t| **t == 4).count(), 100);
     }
+
+    #[test]
+    fn push_slice_single() {
+        let mut v = ArcLog::new();
+        assert_eq!(v.push_slice_spin(&[1, 2, 3]), 0);
+        assert_eq!(v.push_slice_or_return(&[4, 5]), Ok(3));
+        assert_eq!(v.push_slice_spin(&[]), 5);
+        assert_eq!(v.extend(6..20), 5);
+        assert_eq!(v.len(), 19);
+        assert!(v.iter().copied().eq(1..20));
+    }
+
+    #[test]
+    fn push_slice_mt_contiguous() {
+        let mut v = ArcLog::new();
+        let handles: Vec<_> = (1..5usize)
+            .map(|id| {
+                let mut v2 = v.clone();
+                thread::spawn(move || {
+                    let mut starts = Vec::new();
+                    for _i in 0..50 {
+                        if id % 2 == 0 {
+                            starts.push(v2.push_slice_spin(&[id; 7]));
+                        } else {
+                            starts.push(v2.extend(core::iter::repeat_n(id, 7)));
+                        }
+                    }
+                    (id, starts)
+                })
+            })
+            .collect();
+        let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        v.update();
+        assert_eq!(v.len(), 4 * 50 * 7);
+        for (id, starts) in results {
+            for start in starts {
+                assert!(v[start..start + 7].iter().all(|t| *t == id));
+            }
+        }
+    }
+
+    #[test]
+    fn wait_for_len_wakes() {
+        use futures::executor::block_on;
+        let mut v = ArcLog::new();
+        let mut v2 = v.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..20 {
+                v2.push_spin(i);
+                thread::yield_now();
+            }
+        });
+        block_on(v.wait_for_len(20));
+        assert_eq!(v.len(), 20);
+        handle.join().unwrap();
+    }
+
+    #[cfg(feature = "futures-core")]
+    #[test]
+    fn tail_yields_new_ranges() {
+        use futures::executor::block_on;
+        use futures::StreamExt;
+        let mut v = ArcLog::new();
+        v.push_spin(0);
+        let mut tail = v.tail();
+        let mut v2 = v.clone();
+        let handle = thread::spawn(move || {
+            for i in 1..50 {
+                v2.push_spin(i);
+            }
+        });
+        let mut next = 1;
+        block_on(async {
+            while next < 50 {
+                let range = tail.next().await.unwrap();
+                assert_eq!(range.start, next);
+                for i in range.clone() {
+                    assert_eq!(tail.log()[i], i);
+                }
+                next = range.end;
+            }
+        });
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn compact_keeps_indices_and_drops_prefix() {
+        use core::sync::atomic::Ordering::Relaxed;
+        static DROPPED: AtomicUsize = AtomicUsize::new(0);
+        struct Counted(usize);
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                DROPPED.fetch_add(1, Relaxed);
+            }
+        }
+        let mut v = ArcLog::with_capacity(4);
+        for i in 0..4 {
+            assert_eq!(v.push_spin(Counted(i)), i);
+        }
+        let old = v.clone();
+        v.compact_before(3);
+        // the next push has to reallocate, and only copies from index 3 on
+        assert_eq!(v.push_spin(Counted(4)), 4);
+        v.update();
+        assert_eq!(v.start_index(), 3);
+        assert_eq!(v.end_index(), 5);
+        assert_eq!(v.len(), 2);
+        assert_eq!(v[3].0, 3);
+        assert_eq!(v[4].0, 4);
+        assert_eq!(v[3..].len(), 2);
+        // the old allocation still sees everything
+        assert_eq!(old.start_index(), 0);
+        assert_eq!(old[0].0, 0);
+        assert_eq!(DROPPED.load(Relaxed), 0);
+        drop(old);
+        assert_eq!(DROPPED.load(Relaxed), 3);
+        drop(v);
+        assert_eq!(DROPPED.load(Relaxed), 5);
+    }
+
+    #[test]
+    #[should_panic]
+    fn compacted_index_panics() {
+        let mut v = ArcLog::with_capacity(4);
+        for i in 0..5usize {
+            v.push_spin(i);
+        }
+        v.compact_before(4);
+        for i in 5..9usize {
+            v.push_spin(i);
+        }
+        v.update();
+        let _ = v[0];
+    }
+
+    #[test]
+    fn empty_batch_returns_the_global_end() {
+        let mut v = ArcLog::with_capacity(4);
+        let mut stale = v.clone();
+        for i in 0..4usize {
+            v.push_spin(i);
+        }
+        v.compact_before(3);
+        assert_eq!(v.push_spin(4), 4);
+        // start 3 and len 2 once it's on the new allocation, the next index is 5
+        assert_eq!(v.push_slice_spin(&[]), 5);
+        assert_eq!(v.push_slice_or_return(&[]), Ok(5));
+        assert_eq!(v.extend(core::iter::empty()), 5);
+        assert_eq!((v.start_index(), v.len()), (3, 2));
+        for i in 5..10usize {
+            v.push_spin(i);
+        }
+        // still on the first allocation, which only knows about 4 items
+        assert_eq!(stale.len(), 4);
+        assert_eq!(stale.push_slice_spin(&[]), 10);
+        assert_eq!(stale.extend(core::iter::empty()), 10);
+    }
+
+    #[test]
+    fn try_push_hands_item_back_and_unlocks() {
+        use arc_log::TryReserveErrorKind;
+        use crate::common::Counting;
+
+        // no allocation gets through until the budget is raised
+        let alloc = Counting::with_budget(0);
+        let err = ArcLog::<u64, _>::try_with_capacity_in(4, alloc.clone()).unwrap_err();
+        assert!(matches!(err.kind(), TryReserveErrorKind::AllocError { .. }));
+
+        alloc.set_budget(2);
+        let mut v = ArcLog::try_with_capacity_in(4, alloc.clone()).unwrap();
+        for i in 0..4u64 {
+            assert_eq!(v.try_push_spin(i).unwrap(), i as usize);
+        }
+        let err = v.try_push_spin(4).unwrap_err();
+        assert!(matches!(err.error().kind(), TryReserveErrorKind::AllocError { .. }));
+        assert_eq!(err.into_inner(), 4);
+        assert_eq!(
+            v.try_reserve(usize::MAX).unwrap_err().kind(),
+            TryReserveErrorKind::CapacityOverflow
+        );
+        // if the failed grow had left the lock bit set this would spin forever
+        let mut other = v.clone();
+        assert_eq!(other.try_push_spin(4).unwrap_err().into_inner(), 4);
+        alloc.set_budget(1);
+        v.try_reserve(10).unwrap();
+        assert_eq!(other.push_or_return(4), Ok(4));
+        other.update();
+        assert_eq!(&other[..], &[0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn growth_policies_reserve_and_shrink() {
+        use arc_log::{Capped, FixedChunk, GrowthPolicy, OneAndAHalf};
+        assert_eq!(OneAndAHalf.next_capacity(8, 9), 12);
+        assert_eq!(FixedChunk(3).next_capacity(4, 5), 7);
+        assert_eq!(Capped { max_step: 16 }.next_capacity(8, 9), 16);
+        assert_eq!(Capped { max_step: 16 }.next_capacity(64, 65), 80);
+
+        let mut v = ArcLog::with_policy(4, FixedChunk(3));
+        for i in 0..5usize {
+            v.push_spin(i);
+        }
+        v.update();
+        assert_eq!(v.capacity(), 7);
+        // a chunk smaller than the write still fits the whole write
+        v.push_slice_spin(&[5, 6, 7, 8, 9, 10, 11]);
+        v.update();
+        assert_eq!(v.capacity(), 12);
+
+        let mut v = ArcLog::with_capacity(4);
+        v.push_spin(0usize);
+        v.reserve(200);
+        assert!(v.capacity() >= 201);
+        let cap = v.capacity();
+        let mut reader = v.clone();
+        for i in 1..101usize {
+            v.push_spin(i);
+        }
+        // nothing moved, so the old handle sees every push without an update
+        assert!(!reader.update());
+        assert_eq!(reader.len(), 101);
+        assert_eq!(v.capacity(), cap);
+        v.shrink_to_fit();
+        assert_eq!(v.capacity(), 101);
+        assert!(reader.update());
+        assert_eq!(&reader[..], &v[..]);
+    }
+
+    fn mt_push_with<B: arc_log::backoff::Backoff + Default>() {
+        let v = ArcLog::new();
+        let handles: Vec<_> = (0..8usize)
+            .map(|t| {
+                let mut v = v.clone();
+                thread::spawn(move || {
+                    for i in 0..500usize {
+                        v.push_with_backoff(t * 500 + i, B::default());
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        let mut v = v;
+        v.update();
+        let mut seen: Vec<usize> = v.iter().copied().collect();
+        seen.sort_unstable();
+        assert_eq!(seen, (0..4000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn mt_push_with_backoff() {
+        use arc_log::backoff::{Exponential, Spin};
+        mt_push_with::<Spin>();
+        mt_push_with::<Exponential>();
+    }
+
+    #[test]
+    fn exponential_caps_max_step() {
+        use arc_log::backoff::Exponential;
+        // 1 << 32 would overflow the spin count
+        assert_eq!(
+            format!("{:?}", Exponential::new(u32::MAX)),
+            "Exponential { step: 0, max_step: 31 }"
+        );
+        assert_eq!(
+            format!("{:?}", Exponential::new(12)),
+            "Exponential { step: 0, max_step: 12 }"
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn mt_push_with_park_and_yield() {
+        use arc_log::backoff::{Park, SpinThenYield};
+        mt_push_with::<SpinThenYield>();
+        mt_push_with::<Park>();
+    }
+
+    #[test]
+    fn reader_follows_writer() {
+        let mut w = ArcLog::with_capacity(2);
+        let mut r = w.reader();
+        let r2 = r.clone();
+        for i in 0..5usize {
+            w.push_spin(i);
+        }
+        assert!(r.update());
+        assert_eq!(r.len(), 5);
+        assert_eq!(r[4], 4);
+        assert_eq!(&r[1..3], &[1, 2]);
+        assert_eq!((&r).into_iter().sum::<usize>(), 10);
+        // still on the first allocation, which only ever held two
+        assert_eq!(r2.len(), 2);
+        let mut w2 = r.upgrade();
+        w2.push_spin(5);
+        w.update();
+        assert_eq!(&w[..], &[0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn snapshot_is_pinned_and_sendable() {
+        let mut v = ArcLog::with_capacity(16);
+        for i in 0..3usize {
+            v.push_spin(i);
+        }
+        let snap = v.snapshot();
+        // in place, so the snapshot's allocation really does get the new items
+        v.push_spin(3);
+        assert_eq!(v.len(), 4);
+        assert_eq!(&snap[..], &[0, 1, 2]);
+        assert_eq!(snap.end_index(), 3);
+        let snap2 = snap.clone();
+        let sum = thread::spawn(move || snap2.iter().sum::<usize>()).join().unwrap();
+        assert_eq!(sum, 3);
+        for i in 4..40usize {
+            v.push_spin(i);
+        }
+        drop(v);
+        assert_eq!(snap[2], 2);
+        assert_eq!(format!("{:?}", snap), "ArcLogSnapshot { start_index: 0, len: 3, data: [0, 1, 2] }");
+    }
+
+    #[test]
+    fn cursor_follows_forwards() {
+        let mut v = ArcLog::with_capacity(2);
+        let mut c = v.cursor_from(0);
+        let mut late = v.cursor_from(5);
+        assert!(c.next_available().is_none());
+        v.push_spin(DropTest(0));
+        assert_eq!(c.next_available().unwrap().0, 0);
+        for i in 1..8 {
+            v.push_spin(DropTest(i));
+        }
+        // the first allocation only ever held two
+        assert_eq!(c.next_batch().iter().map(|d| d.0).collect::<Vec<_>>(), vec![1]);
+        let mut rest = Vec::new();
+        while c.next_index() < 8 {
+            rest.extend(c.next_batch().iter().map(|d| d.0));
+        }
+        assert_eq!(rest, (2..8).collect::<Vec<_>>());
+        assert!(c.next_batch().is_empty());
+        assert_eq!(late.next_available().unwrap().0, 5);
+        let mut tail = Vec::new();
+        while let Some(d) = late.next_available() {
+            tail.push(d.0);
+        }
+        assert_eq!(tail, vec![6, 7]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let mut v = ArcLog::with_capacity(1);
+        for i in 0..5u32 {
+            v.push_spin(i);
+        }
+        v.update();
+        let json = serde_json::to_string(&v).unwrap();
+        assert_eq!(json, "[0,1,2,3,4]");
+        let w: ArcLog<u32> = serde_json::from_str(&json).unwrap();
+        assert_eq!(&w[..], &v[..]);
+        assert_eq!(w.capacity(), 5);
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    // miri can't map files
+    #[cfg_attr(miri, ignore)]
+    fn mmap_log_grows_and_reopens() {
+        use arc_log::MmapArcLog;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        {
+            let mut v = MmapArcLog::<u64>::create(&path, 2).unwrap();
+            let old = v.clone();
+            for i in 0..10u64 {
+                assert_eq!(v.push_spin(i).unwrap(), i as usize);
+            }
+            assert_eq!(v.push_slice_spin(&[10, 11]).unwrap(), 10);
+            assert!(v.capacity() >= 12);
+            // the first mapping still sees what fit in it
+            assert_eq!(&old[..], &[0, 1]);
+            v.flush().unwrap();
+        }
+        let mut v = MmapArcLog::<u64>::open(&path).unwrap();
+        assert_eq!(&v[..], &(0..12).collect::<Vec<_>>()[..]);
+        v.push_spin(12).unwrap();
+        drop(v);
+        let v = MmapArcLog::<u64>::open(&path).unwrap();
+        assert_eq!(v.len(), 13);
+        assert!(MmapArcLog::<u32>::open(&path).is_err());
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    // miri can't map files
+    #[cfg_attr(miri, ignore)]
+    fn mmap_log_parks_writers_and_grows_by_policy() {
+        use arc_log::backoff::Park;
+        use arc_log::{FixedChunk, MmapArcLog};
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        let v = MmapArcLog::<u64>::create_with_policy(&path, 4, FixedChunk(6)).unwrap();
+        let handles: Vec<_> = (0..4u64)
+            .map(|t| {
+                let mut v = v.clone();
+                thread::spawn(move || {
+                    for i in 0..100u64 {
+                        v.push_with_backoff(t * 100 + i, Park::default()).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        let mut v = v;
+        v.update();
+        // every step added exactly one chunk
+        assert_eq!((v.capacity() - 4) % 6, 0);
+        let mut seen: Vec<u64> = v.iter().copied().collect();
+        seen.sort_unstable();
+        assert_eq!(seen, (0..400).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn epoch_frees_allocations_an_idle_handle_would_pin() {
+        use crate::common::Counting;
+        let alloc = Counting::new();
+        let mut plain = ArcLog::with_capacity_in(1, alloc.clone());
+        let idle = plain.clone();
+        for i in 0..64usize {
+            plain.push_spin(i);
+        }
+        // the shared block, plus every allocation from the idle one's onward
+        assert!(alloc.live() > 3);
+        drop(idle);
+        drop(plain);
+        assert_eq!(alloc.live(), 0);
+
+        let log = ArcLog::with_capacity_in(1, alloc.clone()).into_epoch();
+        let idle = log.clone();
+        for i in 0..64usize {
+            assert_eq!(log.push_spin(i), i);
+        }
+        // just the shared block, the newest allocation and the domain
+        assert_eq!(alloc.live(), 3);
+        let guard = idle.pin();
+        assert_eq!(guard.len(), 64);
+        for i in 64..200usize {
+            log.push_spin(i);
+        }
+        // nothing retired goes while the guard is active
+        assert!(alloc.live() > 4);
+        assert_eq!(guard[63], 63);
+        drop(guard);
+        assert_eq!(alloc.live(), 3);
+        assert_eq!(idle.pin().len(), 200);
+
+        let handles: Vec<_> = (0..4usize)
+            .map(|t| {
+                let log = log.clone();
+                thread::spawn(move || {
+                    for i in 0..500usize {
+                        log.push_spin(1000 + t * 500 + i);
+                        let guard = log.pin();
+                        assert!(guard.len() > 200);
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(log.active_guards(), 0);
+        assert_eq!(alloc.live(), 3);
+        assert_eq!(log.pin().len(), 2200);
+        drop(log);
+        drop(idle);
+        assert_eq!(alloc.live(), 0);
+        // one clone per allocation, all of them dropped
+        assert_eq!(alloc.clones(), 1);
+    }
+
+    #[test]
+    fn zst_tickets_are_unique_under_contention() {
+        let mut v: ArcLog<()> = ArcLog::new();
+        let handles: Vec<_> = (0..4usize)
+            .map(|id| {
+                let mut v2 = v.clone();
+                thread::spawn(move || {
+                    let mut tickets = Vec::new();
+                    for _i in 0..200 {
+                        if id % 2 == 0 {
+                            tickets.push(v2.push_spin(()));
+                        } else {
+                            let first = v2.push_n(3);
+                            tickets.extend(first..first + 3);
+                        }
+                    }
+                    tickets
+                })
+            })
+            .collect();
+        let mut tickets: Vec<usize> = handles.into_iter().flat_map(|h| h.join().unwrap()).collect();
+        tickets.sort_unstable();
+        assert!(tickets.iter().copied().eq(0..2 * 200 + 2 * 200 * 3));
+        v.update();
+        assert_eq!(v.len(), tickets.len());
+        // pushes against an index only land if nothing got in since
+        let end = v.end_index();
+        assert_eq!(v.push_or_return_by_index((), end - 1), Err(()));
+        assert_eq!(v.push_spin_by_index((), end), Ok(end));
+        assert_eq!(v.push_n(0), end + 1);
+        let mut other = v.clone();
+        assert_eq!(other.push_n(0), end + 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "capacity overflow")]
+    fn zst_push_n_past_the_len_encoding_panics() {
+        let mut v: ArcLog<()> = ArcLog::new();
+        // fills the len encoding, the top two bits are the forward and lock flags
+        assert_eq!(v.push_n(usize::MAX >> 2), 0);
+        assert_eq!(v.capacity(), v.len());
+        // past the fetch_add bound, so this one is checked exactly
+        v.push_n(1);
+    }
+
+    #[test]
+    fn push_if_dedups_under_contention() {
+        let mut v = ArcLog::with_capacity(1);
+        let handles: Vec<_> = (0..4usize)
+            .map(|_| {
+                let mut v2 = v.clone();
+                thread::spawn(move || {
+                    (0..50usize)
+                        .filter(|x| v2.push_if(*x, |items| !items.contains(x)).is_ok())
+                        .count()
+                })
+            })
+            .collect();
+        let pushed: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+        assert_eq!(pushed, 50);
+        v.update();
+        let mut items = v.to_vec();
+        items.sort_unstable();
+        assert!(items.into_iter().eq(0..50));
+        // only if the last entry is the one before
+        assert_eq!(v.push_if(7, |items| items.last() == Some(&6)), Err(7));
+        let last = v[v.len() - 1];
+        assert_eq!(v.push_if(last + 1, |items| items.last() == Some(&last)), Ok(50));
+    }
+
+    #[test]
+    fn push_if_unlocks_when_the_predicate_panics() {
+        let mut v = ArcLog::new();
+        v.push_spin(1usize);
+        let mut v2 = v.clone();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            v2.push_if(2, |_| panic!("predicate"))
+        }));
+        assert!(result.is_err());
+        assert_eq!(v.push_spin(3), 1);
+        let mut units: ArcLog<()> = ArcLog::new();
+        units.push_n(2);
+        assert_eq!(units.push_if((), |items| items.len() < 3), Ok(2));
+        assert_eq!(units.push_if((), |items| items.len() < 3), Err(()));
+    }
 }
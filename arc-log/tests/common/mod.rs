@@ -0,0 +1,58 @@
+// allocators shared by the test binaries, not every binary uses all of it
+#![allow(dead_code)]
+
+use std::alloc::{AllocError, Allocator, Global, Layout};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+use std::sync::Arc;
+
+/// Counts the allocations that are still live. Allocating fails once the budget
+/// runs out, it's unlimited unless set.
+#[derive(Clone)]
+pub struct Counting {
+    live: Arc<AtomicUsize>,
+    budget: Arc<AtomicUsize>,
+}
+
+impl Counting {
+    pub fn new() -> Self {
+        Self::with_budget(usize::MAX)
+    }
+
+    pub fn with_budget(budget: usize) -> Self {
+        Counting {
+            live: Arc::new(AtomicUsize::new(0)),
+            budget: Arc::new(AtomicUsize::new(budget)),
+        }
+    }
+
+    /// allocations made and not yet freed
+    pub fn live(&self) -> usize {
+        self.live.load(SeqCst)
+    }
+
+    /// how many more allocations may succeed, frees don't give any back
+    pub fn set_budget(&self, budget: usize) {
+        self.budget.store(budget, SeqCst);
+    }
+
+    /// clones of this allocator still around, this one included
+    pub fn clones(&self) -> usize {
+        Arc::strong_count(&self.live)
+    }
+}
+
+unsafe impl Allocator for Counting {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if self.budget.fetch_update(SeqCst, SeqCst, |n| n.checked_sub(1)).is_err() {
+            return Err(AllocError);
+        }
+        self.live.fetch_add(1, SeqCst);
+        Global.allocate(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.live.fetch_sub(1, SeqCst);
+        Global.deallocate(ptr, layout)
+    }
+}
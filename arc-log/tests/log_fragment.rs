@@ -0,0 +1,76 @@
+
+#[cfg(test)]
+mod tests {
+    use arc_log::LogFragment;
+    use std::sync::atomic::{AtomicUsize, Ordering::*};
+    use std::thread;
+
+    static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Debug)]
+    struct DropTest(usize);
+
+    impl Drop for DropTest {
+        fn drop(&mut self) {
+            DROPPED.fetch_add(1, SeqCst);
+        }
+    }
+
+    #[test]
+    fn lookup_across_fragments() {
+        let mut v = LogFragment::new();
+        for i in 0..100 {
+            assert_eq!(v.push_spin(DropTest(i)), i);
+        }
+        let first = &v[3] as *const DropTest;
+        for i in 100..1000 {
+            v.push_spin(DropTest(i));
+        }
+        // growing never moves existing items
+        assert_eq!(first, &v[3] as *const DropTest);
+        assert_eq!(v.len(), 1000);
+        assert!(v.get(1000).is_none());
+        assert!((0..1000).all(|i| v[i].0 == i));
+        let lens: Vec<_> = v.chunks().map(|c| c.len()).collect();
+        assert_eq!(lens, vec![4, 8, 16, 32, 64, 128, 256, 492]);
+        assert!(v.iter().map(|d| d.0).eq(0..1000));
+        drop(v);
+        assert_eq!(DROPPED.load(SeqCst), 1000);
+    }
+
+    #[test]
+    fn by_index_and_interior_mutability() {
+        let mut v = LogFragment::with_capacity(2);
+        // atomics aren't Freeze, so they can't go in an ArcLog
+        assert_eq!(v.push_or_return(AtomicUsize::new(0)).ok(), Some(0));
+        assert!(v.push_spin_by_index(AtomicUsize::new(1), 0).is_err());
+        assert_eq!(v.push_or_return_by_index(AtomicUsize::new(1), 1).ok(), Some(1));
+        let first = &v[0];
+        let mut w = v.clone();
+        w.push_spin(AtomicUsize::new(2));
+        first.store(5, SeqCst);
+        assert_eq!(v[0].load(SeqCst), 5);
+    }
+
+    #[test]
+    fn mt_push() {
+        let v = LogFragment::new();
+        let handles: Vec<_> = (0..4)
+            .map(|id| {
+                let mut v2 = v.clone();
+                thread::spawn(move || {
+                    for _i in 0..250 {
+                        v2.push_spin(id);
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(v.len(), 1000);
+        for id in 0..4 {
+            assert_eq!(v.iter().filter(|t| **t == id).count(), 250);
+        }
+    }
+}
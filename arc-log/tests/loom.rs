@@ -0,0 +1,87 @@
+// model tests, run with RUSTFLAGS="--cfg loom" cargo test --test loom --release
+#![cfg(loom)]
+#![feature(allocator_api)]
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use arc_log::ArcLog;
+    use loom::thread;
+    // its counter is a plain std atomic, outside the model on purpose
+    use crate::common::Counting;
+
+    #[test]
+    fn push_racing_grow() {
+        loom::model(|| {
+            // room for one, so whoever comes second has to grow
+            let mut log = ArcLog::with_capacity(1);
+            let mut other = log.clone();
+            let t = thread::spawn(move || other.push_spin(1usize));
+            let i = log.push_spin(2usize);
+            let j = t.join().unwrap();
+            assert_ne!(i, j);
+            log.update();
+            let mut items = log.to_vec();
+            items.sort_unstable();
+            assert_eq!(items, [1, 2]);
+        });
+    }
+
+    #[test]
+    fn update_racing_drop_ref() {
+        loom::model(|| {
+            let mut log = ArcLog::with_capacity(1);
+            log.push_spin(1usize);
+            let mut reader = log.clone();
+            // the push grows and sets the forward, then both handles let go
+            let w = thread::spawn(move || {
+                log.push_spin(2usize);
+            });
+            let r = thread::spawn(move || {
+                reader.update();
+                reader.to_vec()
+            });
+            w.join().unwrap();
+            let seen = r.join().unwrap();
+            assert!(seen == [1] || seen == [1, 2]);
+        });
+    }
+
+    #[test]
+    fn zst_tickets_racing_conditional_push() {
+        loom::model(|| {
+            let mut log: ArcLog<()> = ArcLog::new();
+            let mut other = log.clone();
+            let t = thread::spawn(move || other.push_n(2));
+            // lands only if it got in before the push_n
+            let first = log.push_or_return_by_index((), 0);
+            let n = t.join().unwrap();
+            match first {
+                Ok(i) => assert_eq!((i, n), (0, 1)),
+                Err(()) => assert_eq!(n, 0),
+            }
+            log.update();
+            assert_eq!(log.len(), 2 + first.is_ok() as usize);
+        });
+    }
+
+    #[test]
+    fn epoch_reclaim_racing_new_guard() {
+        loom::model(|| {
+            let alloc = Counting::new();
+            let mut log = ArcLog::with_capacity_in(1, alloc.clone());
+            log.push_spin(1usize);
+            let log = log.into_epoch();
+            let other = log.clone();
+            // grows and retires the first allocation while the other guard comes and goes
+            let t = thread::spawn(move || {
+                other.push_spin(2usize);
+            });
+            assert!(log.pin().len() >= 1);
+            t.join().unwrap();
+            assert_eq!(log.active_guards(), 0);
+            // just the shared block, the newest allocation and the domain
+            assert_eq!(alloc.live(), 3);
+        });
+    }
+}
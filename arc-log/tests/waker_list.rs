@@ -0,0 +1,65 @@
+
+#[cfg(test)]
+mod tests {
+    use arc_log::WakerHeader;
+    use std::sync::atomic::{AtomicUsize, Ordering::*};
+    use std::sync::Arc;
+    use std::task::{Wake, Waker};
+
+    struct CountWaker(AtomicUsize);
+
+    impl Wake for CountWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, SeqCst);
+        }
+    }
+
+    fn count_waker() -> (Arc<CountWaker>, Waker) {
+        let count = Arc::new(CountWaker(AtomicUsize::new(0)));
+        (count.clone(), Waker::from(count))
+    }
+
+    #[test]
+    fn wakes_all_and_grows() {
+        let list = WakerHeader::<2>::new();
+        let wakers: Vec<_> = (0..9).map(|_| count_waker()).collect();
+        let keys: Vec<_> = wakers.iter().map(|(_, w)| list.register(None, w)).collect();
+        assert_eq!(list.len(), 9);
+        list.wake_all();
+        assert_eq!(list.len(), 0);
+        assert!(wakers.iter().all(|(c, _)| c.0.load(SeqCst) == 1));
+        // woken slots stay claimed and are reused by the next register
+        for (key, (_, w)) in keys.iter().zip(&wakers) {
+            assert_eq!(list.register(Some(*key), w), *key);
+        }
+        list.deregister(keys[3]);
+        list.wake_all();
+        assert_eq!(wakers[3].0 .0.load(SeqCst), 1);
+        assert_eq!(wakers[4].0 .0.load(SeqCst), 2);
+        let (_, w) = count_waker();
+        assert_eq!(list.register(None, &w), keys[3]);
+    }
+
+    #[test]
+    fn mt_register() {
+        let list = Arc::new(WakerHeader::<1>::new());
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let list = list.clone();
+                std::thread::spawn(move || {
+                    let (count, w) = count_waker();
+                    for _i in 0..100 {
+                        let key = list.register(None, &w);
+                        list.deregister(key);
+                    }
+                    let key = list.register(None, &w);
+                    (count, key)
+                })
+            })
+            .collect();
+        let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(list.len(), 4);
+        list.wake_all();
+        assert!(results.iter().all(|(c, _)| c.0.load(SeqCst) == 1));
+    }
+}
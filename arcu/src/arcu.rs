@@ -1,49 +1,102 @@
 use core::{borrow, fmt};
 use core::future::Future;
 use core::isize;
-use core::fmt;
 use core::marker::{PhantomData, Unpin};
+use core::mem::ManuallyDrop;
 use core::ops::{Deref, Drop};
 use core::pin::Pin;
 use core::ptr::{self, NonNull};
-use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering::*};
-use core::task::{Context, Poll, Waker};
+use crate::sync::{AtomicPtr, AtomicUsize, Ordering::*, UnsafeCell};
+use core::task::{Context, Poll};
+use std::alloc::{Allocator, Global};
 use std::process;
-use std::sync::Mutex;
+
+use arc_log::waker_list::{WakerHeader, WakerKey};
+
+#[cfg(feature = "futures-core")]
+use futures_core::Stream;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 //use tracing::{event, instrument, Level};
 
 const MAX_REFCOUNT: usize = (isize::MAX) as usize;
 
-struct ArcuInner<T> {
+struct ArcuInner<T, A: Allocator> {
     count: AtomicUsize,
-    forward: AtomicPtr<ArcuInner<T>>,
+    // weak handles, plus one shared by all the strong ones and one held by the
+    // version before us, so the chain stays walkable while there are weak handles
+    weak: AtomicUsize,
+    forward: AtomicPtr<ArcuInner<T, A>>,
+    // one more than the version it was published after, set before it's published.
+    // Under loom every read is checked to come after that write
+    version: UnsafeCell<usize>,
     data: T,
-    // you only need to keep track of latest waker
-    callback: NonNull<Mutex<Option<Waker>>>,
-    cb_phantom: PhantomData<Mutex<Option<Waker>>>
+    // shared by every version in the lineage, owned by the last one
+    callback: NonNull<Wakers<A>>,
+    cb_phantom: PhantomData<Wakers<A>>,
+    // each version frees itself, and the last one the callback, through its own clone
+    alloc: A,
 }
 
-pub struct Arcu<T> {
-    ptr: NonNull<ArcuInner<T>>,
-    phantom: PhantomData<ArcuInner<T>>,
+// grows from the lineage's allocator too
+type Wakers<A> = WakerHeader<4, A>;
+
+pub struct Arcu<T, A: Allocator = Global> {
+    ptr: NonNull<ArcuInner<T, A>>,
+    phantom: PhantomData<ArcuInner<T, A>>,
+    // slot in the lineage's waker list, claimed the first time we're polled
+    waker_key: Option<WakerKey>,
 }
 
-impl<T> Future for Arcu<T> {
+impl<T, A: Allocator> Future for Arcu<T, A> {
     type Output = Self;
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let updated = self.update();
-        if updated {
-            Poll::Ready(self.clone())
-        } else {
-            let mut lock = unsafe { self.inner().callback.as_ref().lock().expect("lock shouldn't fail")};
-            *lock = Some(cx.waker().clone());
-            Poll::Pending
-        }
+        self.poll_forward(cx, Arcu::update)
+    }
+}
+
+/// Yields every published version in order, one forward hop at a time
+#[cfg(feature = "futures-core")]
+impl<T, A: Allocator> Stream for Arcu<T, A> {
+    type Item = Self;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.poll_forward(cx, Arcu::update).map(Some)
+    }
+}
+
+/// A stream over an Arcu that skips straight to the newest version
+/// each time it wakes, see [`Arcu::latest_only`]
+#[cfg(feature = "futures-core")]
+pub struct LatestOnly<T, A: Allocator = Global> {
+    arcu: Arcu<T, A>,
+}
+
+#[cfg(feature = "futures-core")]
+impl<T, A: Allocator> LatestOnly<T, A> {
+    pub fn into_inner(self) -> Arcu<T, A> {
+        self.arcu
     }
 }
 
-impl<T> Clone for Arcu<T> {
+#[cfg(feature = "futures-core")]
+impl<T, A: Allocator> Deref for LatestOnly<T, A> {
+    type Target = Arcu<T, A>;
+
+    fn deref(&self) -> &Arcu<T, A> {
+        &self.arcu
+    }
+}
+
+#[cfg(feature = "futures-core")]
+impl<T, A: Allocator> Stream for LatestOnly<T, A> {
+    type Item = Arcu<T, A>;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.arcu.poll_forward(cx, Arcu::update_latest).map(Some)
+    }
+}
+
+impl<T, A: Allocator> Clone for Arcu<T, A> {
     #[inline]
     fn clone(&self) -> Self {
         // SAFETY: We have a refence on this thread so it can't be deleted
@@ -53,12 +106,13 @@ impl<T> Clone for Arcu<T> {
         }
         Self {
             ptr: self.ptr,
-            phantom: PhantomData::default(),
+            phantom: PhantomData,
+            waker_key: None,
         }
     }
 }
 
-impl<T> Deref for Arcu<T> {
+impl<T, A: Allocator> Deref for Arcu<T, A> {
     type Target = T;
 
     #[inline]
@@ -67,21 +121,23 @@ impl<T> Deref for Arcu<T> {
     }
 }
 
-impl<T> Unpin for Arcu<T> {}
+impl<T, A: Allocator> Unpin for Arcu<T, A> {}
+unsafe impl<T: Send + Sync, A: Allocator + Send + Sync> Send for Arcu<T, A> {}
+unsafe impl<T: Send + Sync, A: Allocator + Send + Sync> Sync for Arcu<T, A> {}
 
-impl<T> borrow::Borrow<T> for Arcu<T> {
+impl<T, A: Allocator> borrow::Borrow<T> for Arcu<T, A> {
     fn borrow(&self) -> &T {
         &**self
     }
 }
 
-impl<T> AsRef<T> for Arcu<T> {
+impl<T, A: Allocator> AsRef<T> for Arcu<T, A> {
     fn as_ref(&self) -> &T {
         &**self
     }
 }
 
-impl<T: fmt::Debug> fmt::Debug for Arcu<T> {
+impl<T: fmt::Debug, A: Allocator> fmt::Debug for Arcu<T, A> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let inner = self.inner();
         f.debug_struct("Arcu")
@@ -92,30 +148,110 @@ impl<T: fmt::Debug> fmt::Debug for Arcu<T> {
     }    
 }
 
-impl<T> Drop for Arcu<T> {
+/// Serializes the version this handle points at, same as the value it derefs to
+#[cfg(feature = "serde")]
+impl<T: Serialize, A: Allocator> Serialize for Arcu<T, A> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (**self).serialize(serializer)
+    }
+}
+
+/// Deserializes into the first version of a new lineage
+#[cfg(feature = "serde")]
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Arcu<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(Arcu::new)
+    }
+}
+
+impl<T, A: Allocator> Drop for Arcu<T, A> {
     fn drop(&mut self) {
+        if let Some(key) = self.waker_key.take() {
+            // the waker list lives as long as any version, so it has to go before our ref
+            unsafe { self.inner().callback.as_ref().deregister(key) };
+        }
         let ptr = self.ptr.as_ptr();
         unsafe { drop_ref(ptr) };
     }
 }
 
-impl<T> Drop for ArcuInner<T> {
-    fn drop(&mut self) {
-        // I think this can be relaxed because all callers would have acquired right before this
-        let ptr = self.forward.load(Relaxed);
-        if !ptr.is_null() {
-            // drop ref creates a memory barrier
-            unsafe { drop_ref(ptr) };
-        } else {
-            unsafe { ptr::drop_in_place(self.callback.as_ptr()) };
+/// A handle that doesn't keep any version's data alive, see [`Arcu::downgrade`]
+pub struct WeakArcu<T, A: Allocator = Global> {
+    ptr: NonNull<ArcuInner<T, A>>,
+    phantom: PhantomData<ArcuInner<T, A>>,
+}
+
+impl<T, A: Allocator> WeakArcu<T, A> {
+    /// The newest version, if any strong handle on the lineage is left. Versions
+    /// published after the one this was made from are followed too.
+    pub fn upgrade(&self) -> Option<Arcu<T, A>> {
+        // a weak ref holds the weak of every version after it, so they're all allocated
+        let mut ptr = self.ptr.as_ptr();
+        loop {
+            let next = unsafe { (*ptr).forward.load(Acquire) };
+            if next.is_null() {
+                break;
+            }
+            ptr = next;
+        }
+        // every strong ref on the lineage holds the tail, so if it's gone they all are
+        let count = unsafe { &(*ptr).count };
+        let mut n = count.load(Relaxed);
+        loop {
+            if n == 0 {
+                return None;
+            }
+            if n > MAX_REFCOUNT {
+                process::abort();
+            }
+            match count.compare_exchange_weak(n, n + 1, Acquire, Relaxed) {
+                Ok(_) => break,
+                Err(old) => n = old,
+            }
         }
+        let mut arcu = Arcu {
+            ptr: NonNull::new(ptr).unwrap(),
+            phantom: PhantomData,
+            waker_key: None,
+        };
+        // something may have been published while we were getting there
+        arcu.update_latest();
+        Some(arcu)
+    }
+}
+
+impl<T, A: Allocator> Clone for WeakArcu<T, A> {
+    fn clone(&self) -> Self {
+        let old_size = unsafe { self.ptr.as_ref() }.weak.fetch_add(1, Relaxed);
+        if old_size > MAX_REFCOUNT {
+            process::abort();
+        }
+        WeakArcu {
+            ptr: self.ptr,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T, A: Allocator> Drop for WeakArcu<T, A> {
+    fn drop(&mut self) {
+        unsafe { drop_weak(self.ptr.as_ptr()) };
+    }
+}
+
+unsafe impl<T: Send + Sync, A: Allocator + Send + Sync> Send for WeakArcu<T, A> {}
+unsafe impl<T: Send + Sync, A: Allocator + Send + Sync> Sync for WeakArcu<T, A> {}
+
+impl<T, A: Allocator> fmt::Debug for WeakArcu<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(WeakArcu)")
     }
 }
 
 
 
 #[inline]
-unsafe fn drop_ref<T>(ptr: *mut ArcuInner<T>) {
+unsafe fn drop_ref<T, A: Allocator>(ptr: *mut ArcuInner<T, A>) {
     // release here because we want all of the changes before running the destructor
     if (*ptr).count.fetch_sub(1, Release) != 1 {
     } else {
@@ -127,8 +263,31 @@ unsafe fn drop_ref<T>(ptr: *mut ArcuInner<T>) {
 }
 
 #[inline(never)]
-unsafe fn drop_slow<T>(ptr: *mut ArcuInner<T>) {
-    ptr::drop_in_place(ptr);
+unsafe fn drop_slow<T, A: Allocator>(ptr: *mut ArcuInner<T, A>) {
+    // the data goes with the last strong ref, the allocation with the last weak one
+    ptr::drop_in_place(&mut (*ptr).data);
+    // I think this can be relaxed because all callers would have acquired right before this
+    let forward = (*ptr).forward.load(Relaxed);
+    if !forward.is_null() {
+        // drop ref creates a memory barrier
+        drop_ref(forward);
+    } else {
+        drop(Box::from_raw_in((*ptr).callback.as_ptr(), &(*ptr).alloc));
+    }
+    drop_weak(ptr);
+}
+
+unsafe fn drop_weak<T, A: Allocator>(ptr: *mut ArcuInner<T, A>) {
+    if (*ptr).weak.fetch_sub(1, Release) == 1 {
+        (*ptr).weak.load(Acquire);
+        let forward = (*ptr).forward.load(Relaxed);
+        // the data is already gone, just free the memory with the allocator moved out
+        let alloc = ptr::read(&(*ptr).alloc);
+        drop(Box::from_raw_in(ptr as *mut ManuallyDrop<ArcuInner<T, A>>, &alloc));
+        if !forward.is_null() {
+            drop_weak(forward);
+        }
+    }
 }
 
 impl<T> Arcu<T> {
@@ -138,22 +297,13 @@ impl<T> Arcu<T> {
 
     #[inline]
     pub fn new(data: T) -> Arcu<T> {
-        let cb = Box::new(Mutex::new(None));
-        let x: Box<_> = Box::new(ArcuInner {
-            count: AtomicUsize::new(1),
-            forward: AtomicPtr::default(),
-            data,
-            callback: Box::leak(cb).into(),
-            cb_phantom: PhantomData::default()
-        });
-        Arcu {
-            ptr: Box::leak(x).into(),
-            phantom: PhantomData::default(),
-        }
+        Arcu::new_in(data, Global)
     }
+}
 
+impl<T, A: Allocator> Arcu<T, A> {
     #[inline]
-    fn inner(&self) -> &ArcuInner<T> {
+    fn inner(&self) -> &ArcuInner<T, A> {
         unsafe { self.ptr.as_ref() }
     }
 
@@ -161,6 +311,24 @@ impl<T> Arcu<T> {
         self.inner().count.load(Relaxed)
     }
 
+    /// a handle that can get back the newest version while any strong handle is left
+    pub fn downgrade(&self) -> WeakArcu<T, A> {
+        let old_size = self.inner().weak.fetch_add(1, Relaxed);
+        if old_size > MAX_REFCOUNT {
+            process::abort();
+        }
+        WeakArcu {
+            ptr: self.ptr,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Position of this version in the lineage, the first value is version 0 and
+    /// every update after it is one more than the version it follows.
+    pub fn version(&self) -> usize {
+        self.inner().version.with(|v| unsafe { *v })
+    }
+
     pub fn has_update(&self) -> bool {
         let ptr = self.inner().forward.load(Relaxed);
         !ptr.is_null()
@@ -195,7 +363,7 @@ impl<T> Arcu<T> {
     }
 
     #[inline]
-    fn update_ptr(&mut self, ptr: *mut ArcuInner<T>) {
+    fn update_ptr(&mut self, ptr: *mut ArcuInner<T, A>) {
         let cur_ptr = self.ptr;
         let old_size = unsafe { (*ptr).count.fetch_add(1, Relaxed) };
         if old_size > MAX_REFCOUNT {
@@ -208,53 +376,141 @@ impl<T> Arcu<T> {
         }
     }
 
+    #[cfg(feature = "futures-core")]
+    pub fn latest_only(self) -> LatestOnly<T, A> {
+        LatestOnly { arcu: self }
+    }
+
+    pub(crate) fn poll_forward(&mut self, cx: &mut Context<'_>, update: fn(&mut Self) -> bool) -> Poll<Self> {
+        if update(self) {
+            return Poll::Ready(self.clone());
+        }
+        let wakers = unsafe { self.inner().callback.as_ref() };
+        self.waker_key = Some(wakers.register(self.waker_key, cx.waker()));
+        // a version published between the first check and registering the waker
+        // would never wake us, so check again
+        if update(self) {
+            Poll::Ready(self.clone())
+        } else {
+            Poll::Pending
+        }
+    }
+
     #[inline]
-    pub fn update_value(&mut self, data: T) {
+    fn wake_all(&self) {
+        unsafe { self.inner().callback.as_ref().wake_all() };
+    }
+}
+
+impl<T, A: Allocator + Clone> Arcu<T, A> {
+    /// starts a new lineage whose versions and waker list all come from `alloc`
+    pub fn new_in(data: T, alloc: A) -> Arcu<T, A> {
+        let cb = Box::new_in(Wakers::new_in(alloc.clone()), &alloc);
+        let callback = NonNull::from(Box::leak(cb));
+        let x = Box::new_in(ArcuInner {
+            count: AtomicUsize::new(1),
+            weak: AtomicUsize::new(1),
+            forward: AtomicPtr::default(),
+            version: UnsafeCell::new(0),
+            data,
+            callback,
+            cb_phantom: PhantomData,
+            alloc: alloc.clone(),
+        }, &alloc);
+        Arcu {
+            ptr: Box::leak(x).into(),
+            phantom: PhantomData,
+            waker_key: None,
+        }
+    }
+
+    #[inline]
+    fn new_version(&self, data: T) -> *mut ArcuInner<T, A> {
         let inner = self.inner();
-        let x: Box<_> = Box::new(ArcuInner {
+        let x = Box::new_in(ArcuInner {
+            // the version we're published after holds a strong and a weak on us
             count: AtomicUsize::new(1),
+            weak: AtomicUsize::new(2),
             forward: AtomicPtr::default(),
+            version: UnsafeCell::new(0),
             data,
             callback: inner.callback,
-            cb_phantom: PhantomData::default()
-        });
-        let new_ptr = Box::leak(x);
+            cb_phantom: PhantomData,
+            alloc: inner.alloc.clone(),
+        }, &inner.alloc);
+        Box::leak(x)
+    }
+
+    #[inline]
+    pub fn update_value(&mut self, data: T) {
+        let new_ptr = self.new_version(data);
         //let orig_ptr 
-        let mut cur_point = inner as *const _ as *mut ArcuInner<T>;
+        let mut cur_point = self.ptr.as_ptr();
         // we just update the forward pointer, updating self to point to the new reference will be done on deref
         loop {
+            // nobody can see new_ptr until the exchange succeeds
+            let version = unsafe { (*cur_point).version.with(|v| *v) } + 1;
+            unsafe { (*new_ptr).version.with_mut(|v| *v = version) };
             match unsafe {
                 (*cur_point).forward.compare_exchange_weak(
                     ptr::null_mut(),
                     new_ptr,
                     Release,
-                    Relaxed,
+                    // we move on to the version we lost to, so we need its writes too
+                    Acquire,
                 )
             } {
                 Ok(_) => {
-                    let mut cbs = unsafe { inner.callback.as_ref().lock().unwrap() };
-                    if let Some(c) = cbs.take() {
-                        c.wake();
-                    }
+                    self.wake_all();
                     return;
                 }
+                // weak exchanges can fail spuriously, just retry the same node
+                Err(e) if e.is_null() => {}
                 Err(e) => {
                     cur_point = e;
                 }
             }
         }
     }
-}
-// there is a decision to make as to whether we should include Ts
-// and have bound, or ignore them so you can always debug
-impl<T: fmt::Debug> fmt::Debug for Arcu<T> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+
+    /// Publishes `data` only if this handle points at the newest version, so a value
+    /// computed from `self` can never overwrite a version it didn't see. Hands the
+    /// value back otherwise. Like update_value, self isn't moved to the new version.
+    pub fn compare_and_update(&mut self, data: T) -> Result<(), T> {
         let inner = self.inner();
-        f.debug_struct("Arcu")
-            .field("ptr", &self.ptr)
-            .field("forward", &inner.forward.load(Relaxed))
-            .field("count", &inner.count.load(Relaxed))
-            .field("data", &inner.data)
-            .finish()
+        if !inner.forward.load(Relaxed).is_null() {
+            return Err(data);
+        }
+        let new_ptr = self.new_version(data);
+        let version = inner.version.with(|v| unsafe { *v }) + 1;
+        unsafe { (*new_ptr).version.with_mut(|v| *v = version) };
+        match inner
+            .forward
+            .compare_exchange(ptr::null_mut(), new_ptr, Release, Relaxed)
+        {
+            Ok(_) => {
+                self.wake_all();
+                Ok(())
+            }
+            Err(_) => {
+                // nobody else ever saw it, so we can take the data back out.
+                // ManuallyDrop keeps the box from dropping the data we're handing back
+                let unpublished = unsafe { Box::from_raw_in(new_ptr as *mut ManuallyDrop<ArcuInner<T, A>>, &inner.alloc) };
+                drop(unsafe { ptr::read(&unpublished.alloc) });
+                Err(unsafe { ptr::read(&unpublished.data) })
+            }
+        }
     }
-}
\ No newline at end of file
+
+    /// Read, copy, update: recomputes `f` against the newest version until it can
+    /// publish without another writer getting in between.
+    pub fn rcu<F: FnMut(&T) -> T>(&mut self, mut f: F) {
+        loop {
+            self.update_latest();
+            let data = f(&**self);
+            if self.compare_and_update(data).is_ok() {
+                return;
+            }
+        }
+    }
+}
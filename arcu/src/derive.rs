@@ -0,0 +1,199 @@
+use core::future::Future;
+use core::marker::Unpin;
+use core::ops::Deref;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use std::alloc::{Allocator, Global};
+
+#[cfg(feature = "futures-core")]
+use futures_core::Stream;
+
+use crate::arcu::Arcu;
+
+/// What a [`Derived`] value is computed from, one Arcu or a pair of them
+pub trait Source {
+    /// moves every handle to its newest version, true if any of them moved
+    fn update_latest(&mut self) -> bool;
+
+    /// ready once any handle has moved, registers `cx` otherwise
+    fn poll_latest(&mut self, cx: &mut Context<'_>) -> Poll<()>;
+}
+
+impl<T, A: Allocator> Source for Arcu<T, A> {
+    fn update_latest(&mut self) -> bool {
+        Arcu::update_latest(self)
+    }
+
+    fn poll_latest(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        self.poll_forward(cx, Arcu::update_latest).map(drop)
+    }
+}
+
+/// The source of a value made by [`Arcu::zip`]
+pub type Zipped<T, B, A = Global, AB = Global> = (Arcu<T, A>, Arcu<B, AB>);
+
+impl<T, B, A: Allocator, AB: Allocator> Source for Zipped<T, B, A, AB> {
+    fn update_latest(&mut self) -> bool {
+        // no short circuit, both have to move
+        self.0.update_latest() | self.1.update_latest()
+    }
+
+    fn poll_latest(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        // poll both so each one has our waker registered
+        let a = self.0.poll_latest(cx);
+        let b = self.1.poll_latest(cx);
+        if a.is_ready() || b.is_ready() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// The function a [`Derived`] value is recomputed with
+pub trait Recompute<S, U> {
+    fn recompute(&mut self, source: &S) -> U;
+}
+
+impl<T, A: Allocator, U, F: FnMut(&T) -> U> Recompute<Arcu<T, A>, U> for F {
+    fn recompute(&mut self, source: &Arcu<T, A>) -> U {
+        self(&**source)
+    }
+}
+
+impl<T, B, A: Allocator, AB: Allocator, U, F: FnMut(&T, &B) -> U>
+    Recompute<Zipped<T, B, A, AB>, U> for F
+{
+    fn recompute(&mut self, source: &Zipped<T, B, A, AB>) -> U {
+        self(&source.0, &source.1)
+    }
+}
+
+/// A value computed from one or more Arcus, see [`Arcu::derive`] and [`Arcu::zip`].
+///
+/// Each recompute is published as a new version of an ordinary Arcu, so any number
+/// of readers can follow it with [`arcu`](Self::arcu). Only this handle recomputes:
+/// lazily on [`update`](Self::update), or eagerly as a stream or through
+/// [`into_task`](Self::into_task).
+///
+/// Readers never recompute. Updating a handle from `arcu` only moves it to the last
+/// value this handle published, so if the source has moved since and nobody calls
+/// `update` or drives the stream or task, readers keep seeing the old value.
+pub struct Derived<S, U, F> {
+    source: S,
+    value: Arcu<U>,
+    f: F,
+}
+
+impl<T, A: Allocator> Arcu<T, A> {
+    /// A value computed from this one that can follow it as it's updated. The
+    /// returned [`Derived`] is what recomputes: lazily when its own `update` is
+    /// called, or eagerly as a stream or through [`Derived::into_task`]. Readers
+    /// from [`Derived::arcu`] are plain Arcus, their `update` only moves them to
+    /// the last value the Derived published and never recomputes.
+    pub fn derive<U, F: FnMut(&T) -> U>(&self, f: F) -> Derived<Arcu<T, A>, U, F> {
+        Derived::new(self.clone(), f)
+    }
+
+    /// A value computed from this one and `other`, recomputed when either is
+    /// updated. Like [`derive`](Self::derive), only the returned [`Derived`]
+    /// recomputes, its readers just follow what it publishes.
+    pub fn zip<B, AB: Allocator, U, F: FnMut(&T, &B) -> U>(
+        &self,
+        other: &Arcu<B, AB>,
+        f: F,
+    ) -> Derived<Zipped<T, B, A, AB>, U, F> {
+        Derived::new((self.clone(), other.clone()), f)
+    }
+}
+
+impl<S: Source, U, F: Recompute<S, U>> Derived<S, U, F> {
+    fn new(mut source: S, mut f: F) -> Self {
+        source.update_latest();
+        let value = Arcu::new(f.recompute(&source));
+        Derived { source, value, f }
+    }
+
+    // publishes a new value computed from the source as it is now
+    fn publish(&mut self) {
+        let data = self.f.recompute(&self.source);
+        self.value.update_value(data);
+        self.value.update_latest();
+    }
+
+    /// Recomputes if the source has been updated since last time, returns false if
+    /// it hasn't. Readers from [`arcu`](Self::arcu) see the new value on their update.
+    pub fn update(&mut self) -> bool {
+        if self.source.update_latest() {
+            self.publish();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// A handle on the derived value, it follows every recompute. It can't trigger
+    /// one, its updates only see what this handle has published.
+    pub fn arcu(&self) -> Arcu<U> {
+        self.value.clone()
+    }
+
+    pub fn source(&self) -> &S {
+        &self.source
+    }
+
+    /// Turns this into a future that recomputes each time the source is updated, for
+    /// spawning on an executor. It finishes the next time the source is updated after
+    /// the last handle on the derived value is gone.
+    pub fn into_task(self) -> (Arcu<U>, DeriveTask<S, U, F>) {
+        (self.arcu(), DeriveTask { derived: self })
+    }
+}
+
+impl<S, U, F> Deref for Derived<S, U, F> {
+    type Target = U;
+
+    fn deref(&self) -> &U {
+        &self.value
+    }
+}
+
+impl<S, U, F> Unpin for Derived<S, U, F> {}
+
+/// Recomputes whenever the source is updated, yielding the new value
+#[cfg(feature = "futures-core")]
+impl<S: Source, U, F: Recompute<S, U>> Stream for Derived<S, U, F> {
+    type Item = Arcu<U>;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.source.poll_latest(cx) {
+            Poll::Ready(()) => {
+                self.publish();
+                Poll::Ready(Some(self.arcu()))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Keeps a derived value up to date in the background, see [`Derived::into_task`]
+pub struct DeriveTask<S, U, F> {
+    derived: Derived<S, U, F>,
+}
+
+impl<S: Source, U, F: Recompute<S, U>> Future for DeriveTask<S, U, F> {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let derived = &mut self.derived;
+        loop {
+            // we're on the newest version, so if we hold the only reference no older
+            // version is alive either and nobody can ever read another recompute
+            if derived.value.ref_count() == 1 {
+                return Poll::Ready(());
+            }
+            match derived.source.poll_latest(cx) {
+                Poll::Ready(()) => derived.publish(),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use core::fmt;
+use std::alloc::{Allocator, Global};
+use std::collections::VecDeque;
+
+use crate::arcu::Arcu;
+
+/// Keeps the last `len` versions of an Arcu alive, for audit or undo.
+///
+/// Versions older than that are only kept by handles still pointing at them, once
+/// those are gone they drop like they would without a history.
+pub struct ArcuHistory<T, A: Allocator = Global> {
+    // oldest first, always one hop apart
+    versions: VecDeque<Arcu<T, A>>,
+    len: usize,
+}
+
+impl<T, A: Allocator> ArcuHistory<T, A> {
+    /// starts a history at the version `arcu` points to, keeping up to `len` versions
+    pub fn new(arcu: &Arcu<T, A>, len: usize) -> Self {
+        assert!(len > 0, "a history has to keep at least one version");
+        let mut versions = VecDeque::with_capacity(len);
+        versions.push_back(arcu.clone());
+        ArcuHistory { versions, len }
+    }
+
+    /// Records every version published since the last update, dropping the oldest
+    /// ones past `len`. Returns false if there wasn't a new one.
+    pub fn update(&mut self) -> bool {
+        let mut next = self.latest().clone();
+        if !next.update() {
+            return false;
+        }
+        loop {
+            if self.versions.len() == self.len {
+                self.versions.pop_front();
+            }
+            self.versions.push_back(next.clone());
+            if !next.update() {
+                return true;
+            }
+        }
+    }
+
+    /// the newest recorded version
+    pub fn latest(&self) -> &Arcu<T, A> {
+        self.versions.back().unwrap()
+    }
+
+    /// the recorded handle for `version`, None if it was never recorded or was dropped
+    pub fn get(&self, version: usize) -> Option<&Arcu<T, A>> {
+        let oldest = self.versions.front().unwrap().version();
+        self.versions.get(version.checked_sub(oldest)?)
+    }
+
+    /// the recorded versions, oldest first
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Arcu<T, A>> + ExactSizeIterator {
+        self.versions.iter()
+    }
+
+    /// how many versions it keeps at most
+    pub fn capacity(&self) -> usize {
+        self.len
+    }
+}
+
+impl<T, A: Allocator> Clone for ArcuHistory<T, A> {
+    fn clone(&self) -> Self {
+        ArcuHistory {
+            versions: self.versions.clone(),
+            len: self.len,
+        }
+    }
+}
+
+impl<T: fmt::Debug, A: Allocator> fmt::Debug for ArcuHistory<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.versions.iter().map(|v| (v.version(), &**v)))
+            .finish()
+    }
+}
@@ -1,3 +1,12 @@
+#![feature(allocator_api)]
+
+mod sync;
+
 pub mod arcu;
 pub use arcu::*;
 
+pub mod derive;
+pub use derive::*;
+
+pub mod history;
+pub use history::*;
@@ -1,10 +1,13 @@
+#![feature(allocator_api)]
+mod common;
+
 
 #[cfg(test)]
 mod tests {
     //use arc_log::ArcLog;
     use core::sync::atomic::AtomicUsize;
     use std::thread;
-    use arcu::Arcu;
+    use arcu::{Arcu, ArcuHistory};
     use tracing::{event, instrument, Level, field::debug};
     use tracing_subscriber;
 
@@ -78,4 +81,264 @@ fn clone_update_drop_clone() {
     assert!(10 == *v2);
     assert!(v2.ref_count() == 1);
 }
-}
\ No newline at end of file
+
+#[cfg(feature = "futures-core")]
+#[test]
+fn stream_yields_every_version() {
+    use futures::executor::block_on;
+    use futures::StreamExt;
+    let mut v = Arcu::new(0);
+    let mut writer = v.clone();
+    for i in 1..4 {
+        writer.update_value(i);
+    }
+    block_on(async {
+        for i in 1..4 {
+            let next = v.next().await.unwrap();
+            assert_eq!(*next, i);
+            assert_eq!(*v, i);
+        }
+    });
+    let mut latest = writer.latest_only();
+    let next = block_on(latest.next()).unwrap();
+    assert_eq!(*next, 3);
+}
+
+#[cfg(feature = "futures-core")]
+#[test]
+fn stream_wakes_on_publish() {
+    use futures::executor::LocalPool;
+    use futures::task::LocalSpawnExt;
+    use futures::StreamExt;
+    let mut pool = LocalPool::new();
+    let mut cfg = Arcu::new(0);
+    let mut writer = cfg.clone();
+    let reader = pool
+        .spawner()
+        .spawn_local_with_handle(async move {
+            let mut seen = Vec::new();
+            while let Some(v) = cfg.next().await {
+                seen.push(*v);
+                if *v == 2 {
+                    break;
+                }
+            }
+            seen
+        })
+        .unwrap();
+    pool.run_until_stalled();
+    writer.update_value(1);
+    pool.run_until_stalled();
+    writer.update_value(2);
+    assert_eq!(pool.run_until(reader), vec![1, 2]);
+}
+
+#[test]
+fn every_waiter_is_woken() {
+    use futures::executor::LocalPool;
+    use futures::task::LocalSpawnExt;
+    let mut pool = LocalPool::new();
+    let mut writer = Arcu::new(0);
+    let handles: Vec<_> = (0..3)
+        .map(|_| {
+            let reader = writer.clone();
+            pool.spawner()
+                .spawn_local_with_handle(async move { *reader.await })
+                .unwrap()
+        })
+        .collect();
+    pool.run_until_stalled();
+    writer.update_value(7);
+    for handle in handles {
+        assert_eq!(pool.run_until(handle), 7);
+    }
+}
+
+#[test]
+fn compare_and_update_rejects_stale() {
+    let mut a = Arcu::new(0);
+    let mut b = a.clone();
+    b.update_value(1);
+    assert_eq!(a.compare_and_update(5), Err(5));
+    a.update();
+    assert_eq!(a.compare_and_update(2), Ok(()));
+    assert_eq!(*a, 1);
+    b.rcu(|v| v + 1);
+    b.update_latest();
+    assert_eq!(*b, 3);
+}
+
+#[test]
+fn mt_rcu_doesnt_lose_updates() {
+    let v = Arcu::new(0usize);
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let mut v = v.clone();
+            thread::spawn(move || {
+                for _i in 0..100 {
+                    v.rcu(|old| old + 1);
+                }
+            })
+        })
+        .collect();
+    for h in handles {
+        h.join().unwrap();
+    }
+    let mut v = v;
+    v.update_latest();
+    assert_eq!(*v, 400);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde_uses_current_value() {
+    let mut v = Arcu::new(String::from("a"));
+    v.update_value(String::from("b"));
+    assert_eq!(serde_json::to_string(&v).unwrap(), "\"a\"");
+    v.update();
+    assert_eq!(serde_json::to_string(&v).unwrap(), "\"b\"");
+    let w: Arcu<String> = serde_json::from_str("\"c\"").unwrap();
+    assert_eq!(*w, "c");
+}
+
+#[test]
+fn derive_and_zip_follow_sources() {
+    let mut a = Arcu::new(2);
+    let b = Arcu::new(String::from("x"));
+    let mut double = a.derive(|v| v * 2);
+    let mut reader = double.arcu();
+    let mut both = a.zip(&b, |v, s| format!("{}{}", s, v));
+    assert_eq!(*double, 4);
+    assert_eq!(*both, "x2");
+    assert!(!double.update());
+    a.update_value(5);
+    // Lazy mode is the Derived's own update. A reader from arcu() never recomputes,
+    // it only sees what the Derived has published, so it stays on 4 until then
+    assert!(!reader.update());
+    assert_eq!(*reader, 4);
+    assert!(double.update());
+    assert_eq!(*double, 10);
+    assert!(reader.update());
+    assert_eq!(*reader, 10);
+    let mut b = b;
+    b.update_value(String::from("y"));
+    assert!(both.update());
+    assert_eq!(*both, "y5");
+}
+
+#[test]
+fn derive_task_recomputes_until_readers_are_gone() {
+    use futures::executor::LocalPool;
+    use futures::task::LocalSpawnExt;
+    let mut pool = LocalPool::new();
+    let mut src = Arcu::new(1);
+    let (mut value, task) = src.derive(|v| v + 100).into_task();
+    let done = pool.spawner().spawn_local_with_handle(task).unwrap();
+    pool.run_until_stalled();
+    src.update_value(2);
+    pool.run_until_stalled();
+    assert!(value.update_latest());
+    assert_eq!(*value, 102);
+    drop(value);
+    src.update_value(3);
+    pool.run_until(done);
+}
+
+#[cfg(feature = "futures-core")]
+#[test]
+fn derived_stream_yields_recomputes() {
+    use futures::executor::block_on;
+    use futures::StreamExt;
+    let mut src = Arcu::new(1);
+    let mut derived = src.derive(|v| v * 10);
+    src.update_value(2);
+    src.update_value(3);
+    // skips to the newest source version
+    assert_eq!(*block_on(derived.next()).unwrap(), 30);
+}
+
+#[test]
+fn history_keeps_last_versions() {
+    let mut v = Arcu::new(String::from("a"));
+    let mut history = ArcuHistory::new(&v, 3);
+    assert_eq!(v.version(), 0);
+    for s in ["b", "c", "d", "e"].iter() {
+        v.update_value(s.to_string());
+    }
+    assert!(history.update());
+    assert!(!history.update());
+    let seen: Vec<_> = history.iter().map(|v| (v.version(), v.as_str())).collect();
+    assert_eq!(seen, vec![(2, "c"), (3, "d"), (4, "e")]);
+    assert!(history.get(1).is_none());
+    assert_eq!(**history.get(3).unwrap(), "d");
+    assert!(history.get(5).is_none());
+    // undo by publishing an old value again
+    let old = (**history.get(2).unwrap()).clone();
+    v.update_value(old);
+    v.update_latest();
+    assert_eq!((v.version(), v.as_str()), (5, "c"));
+    // versions 0 and 1 are gone, so only the history holds version 2
+    assert_eq!(history.get(2).unwrap().ref_count(), 1);
+}
+
+#[test]
+fn weak_follows_forward_and_lets_data_drop() {
+    use std::sync::atomic::Ordering::SeqCst;
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+    struct Counted(usize);
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPS.fetch_add(1, SeqCst);
+        }
+    }
+    let mut v = Arcu::new(Counted(0));
+    let weak = v.downgrade();
+    v.update_value(Counted(1));
+    v.update_value(Counted(2));
+    v.update_latest();
+    // nothing but the weak handle points at the first two versions anymore
+    assert_eq!(DROPS.load(SeqCst), 2);
+    let up = weak.upgrade().unwrap();
+    assert_eq!((up.version(), up.0), (2, 2));
+    drop(up);
+    let weak2 = weak.clone();
+    drop(weak);
+    drop(v);
+    assert_eq!(DROPS.load(SeqCst), 3);
+    assert!(weak2.upgrade().is_none());
+}
+
+#[test]
+fn new_in_allocates_every_version_from_alloc() {
+    use crate::common::Counting;
+
+    let alloc = Counting::new();
+    let mut v = Arcu::new_in(String::from("a"), alloc.clone());
+    // the version and the waker list
+    assert_eq!(alloc.live(), 2);
+    let old = v.clone();
+    let weak = v.downgrade();
+    v.update_value(String::from("b"));
+    assert_eq!(v.compare_and_update(String::from("c")), Err(String::from("c")));
+    v.update();
+    assert_eq!(alloc.live(), 3);
+    drop(old);
+    // the first version stays allocated for the weak handle
+    assert_eq!(alloc.live(), 3);
+    drop(weak);
+    assert_eq!(alloc.live(), 2);
+    // five waiters don't fit in the inline slots, the overflow block and its slots
+    // come from alloc as well
+    let mut cx = std::task::Context::from_waker(std::task::Waker::noop());
+    let mut waiting: Vec<_> = (0..5).map(|_| v.clone()).collect();
+    for w in &mut waiting {
+        assert!(std::future::Future::poll(std::pin::Pin::new(w), &mut cx).is_pending());
+    }
+    assert_eq!(alloc.live(), 4);
+    drop(waiting);
+    drop(v);
+    assert_eq!(alloc.live(), 0);
+    // one clone per version, all of them dropped
+    assert_eq!(alloc.clones(), 1);
+}
+}
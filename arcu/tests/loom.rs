@@ -0,0 +1,38 @@
+// model tests, run with RUSTFLAGS="--cfg loom" cargo test --test loom --release
+#![cfg(loom)]
+#[cfg(test)]
+mod tests {
+    use arcu::Arcu;
+    use loom::future::block_on;
+    use loom::thread;
+
+    #[test]
+    fn update_value_racing_poll() {
+        loom::model(|| {
+            let reader = Arcu::new(0usize);
+            let mut writer = reader.clone();
+            let t = thread::spawn(move || writer.update_value(1));
+            // has to be woken if the publish lands between its check and its register
+            let next = block_on(reader);
+            assert_eq!(*next, 1);
+            t.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn update_value_chain_walk() {
+        loom::model(|| {
+            let mut a = Arcu::new(0usize);
+            let mut b = a.clone();
+            // both start at version 0, whoever loses the exchange walks forward
+            let t = thread::spawn(move || b.update_value(1));
+            a.update_value(2);
+            t.join().unwrap();
+            let mut seen = Vec::new();
+            while a.update() {
+                seen.push((a.version(), *a));
+            }
+            assert!(seen == [(1, 1), (2, 2)] || seen == [(1, 2), (2, 1)]);
+        });
+    }
+}